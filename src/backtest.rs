@@ -0,0 +1,222 @@
+use calibration::DischargeCurve;
+use estimate::{Direction, Estimate, Estimator};
+use graph::BatteryState;
+use profile::{MAX_SAMPLE_GAP, ProfileEstimator, UsageProfile};
+use std::rc::Rc;
+
+/// Level at which we consider the battery to have run out
+const EMPTY_LEVEL: f32 = 1.0;
+
+/// Machines tend to shut themselves down before they get to `EMPTY_LEVEL`, so going quiet
+/// for a while at or below this level counts as having run out too
+const SHUTDOWN_LEVEL: f32 = 5.0;
+
+/// Width of the level bands errors are broken down by
+const BAND_WIDTH: usize = 20;
+
+/// What actually happened after a sample
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The battery ran out this many seconds later
+    Empty(f64),
+
+    /// The charger was plugged in this many seconds later, so the battery would have lasted at least that long
+    PluggedIn(f64),
+}
+
+/// Running error statistics, in seconds
+#[derive(Default)]
+struct Errors {
+    count:        u32,
+    sum:          f64,
+    sum_absolute: f64,
+    sum_squared:  f64,
+}
+
+impl Errors {
+    fn add(&mut self, error: f64) {
+        self.count        += 1;
+        self.sum          += error;
+        self.sum_absolute += error.abs();
+        self.sum_squared  += error * error;
+    }
+
+    fn print(&self, label: &str) {
+        if self.count == 0 {
+            return println!("{:<24} {:>8}", label, 0);
+        }
+
+        let count = self.count as f64;
+        println!("{:<24} {:>8} {:>9.0}m {:>9.0}m {:>9.0}m",
+                 label,
+                 self.count,
+                 self.sum / count / 60.0,
+                 self.sum_absolute / count / 60.0,
+                 (self.sum_squared / count).sqrt() / 60.0);
+    }
+}
+
+/// Scores for one estimator, overall and per level band
+struct Score {
+    name:    String,
+    overall: Errors,
+    bands:   Vec<Errors>,
+}
+
+impl Score {
+    fn new(name: &str) -> Score {
+        Score {
+            name:    name.to_string(),
+            overall: Errors::default(),
+            bands:   (0..(100 / BAND_WIDTH)).map(|_| Errors::default()).collect(),
+        }
+    }
+}
+
+/// Replays a history sample by sample and reports how far off each estimator was. The last
+/// one is also scored following a discharge curve, the way it runs once calibrated.
+pub fn run(states: &[BatteryState], estimators: Vec<(String, Box<Estimator>)>) {
+    let mut scores = estimators.iter()
+                               .map(|&(ref name, _)| Score::new(name))
+                               .collect::<Vec<_>>();
+
+    let mut profile_score    = Score::new("profile");
+    let mut calibrated_score = Score::new("calibrated");
+
+    // The profile and curve may only learn from what happened before the discharge they're predicting
+    let mut learned = false;
+    let mut profile = ProfileEstimator { profile: UsageProfile::learn(&[]) };
+    let mut curve   = None;
+
+    for index in 0..states.len() {
+        let state = &states[index];
+        if state.get_charging() {
+            learned = false;
+            continue;
+        }
+
+        let outcome = match outcome(states, index) {
+            Some(outcome) => outcome,
+            None          => continue,
+        };
+
+        let band    = (state.get_level().max(0.0) as usize / BAND_WIDTH).min(100 / BAND_WIDTH - 1);
+        let history = &states[..(index + 1)];
+
+        for (&(_, ref estimator), score) in estimators.iter().zip(scores.iter_mut()) {
+            score_estimate(estimator.estimate(history), &outcome, band, score);
+        }
+
+        if !learned {
+            learned = true;
+            profile = ProfileEstimator { profile: UsageProfile::learn(&states[..index]) };
+            curve   = DischargeCurve::fit(&states[..index]).map(Rc::new);
+        }
+
+        score_estimate(profile.estimate(history), &outcome, band, &mut profile_score);
+
+        if let (Some(&(_, ref estimator)), Some(curve)) = (estimators.last(), curve.as_ref()) {
+            let estimate = estimator.estimate(history).map(|estimate| estimate.with_curve(curve.clone()));
+            score_estimate(estimate, &outcome, band, &mut calibrated_score);
+        }
+    }
+
+    println!("{:<24} {:>8} {:>10} {:>10} {:>10}", "estimator", "samples", "bias", "mean abs", "rmse");
+
+    for score in scores.iter().chain(Some(&profile_score)).chain(Some(&calibrated_score)) {
+        score.overall.print(&score.name);
+
+        for (band, errors) in score.bands.iter().enumerate() {
+            errors.print(&format!("  {}-{}%", band * BAND_WIDTH, (band + 1) * BAND_WIDTH));
+        }
+    }
+}
+
+fn score_estimate(estimate: Option<Estimate>, outcome: &Outcome, band: usize, score: &mut Score) {
+    let predicted = match estimate {
+        Some(ref estimate) if estimate.direction == Direction::Discharging => estimate.remaining,
+        _ => return,
+    };
+
+    let error = match *outcome {
+        Outcome::Empty(actual) => predicted - actual,
+
+        // We only know the battery lasted at least this long, so we can only fault pessimism
+        Outcome::PluggedIn(actual) if predicted < actual => predicted - actual,
+        Outcome::PluggedIn(_) => return,
+    };
+
+    score.overall.add(error);
+    score.bands[band].add(error);
+}
+
+/// Finds out how the discharge a sample is part of ended, if we saw it end
+fn outcome(states: &[BatteryState], index: usize) -> Option<Outcome> {
+    let start = states[index].get_timestamp() as i64;
+
+    for pair in states[index..].windows(2) {
+        let gap = pair[1].get_timestamp() as i64 - pair[0].get_timestamp() as i64;
+
+        // Going quiet this low is most likely the machine shutting down for lack of power
+        if gap > MAX_SAMPLE_GAP && pair[0].get_level() <= SHUTDOWN_LEVEL {
+            return Some(Outcome::Empty((pair[0].get_timestamp() as i64 - start) as f64));
+        }
+
+        // Otherwise sleeping or a changed clock makes the real time remaining unknowable
+        if gap < 0 || gap > MAX_SAMPLE_GAP {
+            return None;
+        }
+
+        let elapsed = (pair[1].get_timestamp() as i64 - start) as f64;
+
+        if pair[1].get_charging() {
+            return Some(Outcome::PluggedIn(elapsed));
+        }
+
+        if pair[1].get_level() <= EMPTY_LEVEL {
+            return Some(Outcome::Empty(elapsed));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use fixtures::{run, state};
+    use super::*;
+
+    #[test]
+    fn plugging_in() {
+        let mut states = run(1000, 50.0, -0.5, false, 10);
+        states.push(state(1000 + 10 * 60, 45.0, true));
+
+        assert_eq!(outcome(&states, 0), Some(Outcome::PluggedIn(600.0)));
+        assert_eq!(outcome(&states, 4), Some(Outcome::PluggedIn(360.0)));
+    }
+
+    #[test]
+    fn running_out() {
+        let states = run(1000, 5.0, -0.5, false, 10);
+
+        assert_eq!(outcome(&states, 0), Some(Outcome::Empty(480.0)));
+    }
+
+    #[test]
+    fn shutting_down_before_empty() {
+        // Off for hours at 4%, then back up on the charger
+        let mut states = run(1000, 8.0, -0.5, false, 9);
+        states.push(state(1000 + 8 * 60 + 3600 * 5, 3.0, true));
+
+        assert_eq!(outcome(&states, 0), Some(Outcome::Empty(480.0)));
+    }
+
+    #[test]
+    fn sleeping_with_charge_left() {
+        let mut states = run(1000, 50.0, -0.5, false, 10);
+        states.push(state(1000 + 9 * 60 + 3600 * 5, 40.0, false));
+        states.push(state(1000 + 10 * 60 + 3600 * 5, 40.0, true));
+
+        assert_eq!(outcome(&states, 0), None);
+    }
+}
@@ -0,0 +1,136 @@
+use graph::BatteryState;
+use profile::MAX_SAMPLE_GAP;
+
+/// Cycles that cover less than this many percent say too little about the curve
+const MIN_CYCLE_DROP: f32 = 20.0;
+
+/// Maps the level the firmware reports to how much usable energy is actually left
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DischargeCurve {
+    /// Percent of usable energy left at each whole reported level, from 0 to 100
+    energy: Vec<f64>,
+
+    /// Number of discharge cycles the curve was fitted on
+    pub cycles: u32,
+}
+
+impl DischargeCurve {
+    /// Fits a curve on the discharge cycles in a history, assuming the load stays roughly
+    /// constant within a cycle so time spent per percent tracks energy per percent
+    pub fn fit(states: &[BatteryState]) -> Option<DischargeCurve> {
+        let mut weights = vec![0.0; 100];
+        let mut counts  = vec![0.0; 100];
+        let mut cycles  = 0;
+
+        for cycle in discharge_cycles(states) {
+            let (first, last) = (&cycle[0], &cycle[cycle.len() - 1]);
+
+            let cycle_drop = first.get_level() - last.get_level();
+            if cycle_drop < MIN_CYCLE_DROP {
+                continue;
+            }
+
+            let cycle_time     = (last.get_timestamp() as i64 - first.get_timestamp() as i64) as f64;
+            let mean_per_level = cycle_time / cycle_drop as f64;
+
+            for pair in cycle.windows(2) {
+                let drop = (pair[0].get_level() - pair[1].get_level()) as f64;
+                if drop <= 0.0 {
+                    continue;
+                }
+
+                let time   = (pair[1].get_timestamp() as i64 - pair[0].get_timestamp() as i64) as f64;
+                let middle = (pair[0].get_level() + pair[1].get_level()) as f64 / 2.0;
+                let bucket = (middle.max(0.0) as usize).min(99);
+
+                // Relative time spent per percent, weighted by how many percent this pair covers
+                weights[bucket] += time / mean_per_level;
+                counts[bucket]  += drop;
+            }
+
+            cycles += 1;
+        }
+
+        if cycles == 0 {
+            return None;
+        }
+
+        // Levels we've never seen drained through count as average
+        let mut energy = vec![0.0];
+        for bucket in 0..100 {
+            let weight = if counts[bucket] > 0.0 { weights[bucket] / counts[bucket] } else { 1.0 };
+            let total  = energy[bucket] + weight;
+            energy.push(total);
+        }
+
+        let total = energy[100];
+        for value in energy.iter_mut() {
+            *value = *value / total * 100.0;
+        }
+
+        Some(DischargeCurve {
+            energy: energy,
+            cycles: cycles,
+        })
+    }
+
+    /// Returns the percent of usable energy left at a reported level
+    pub fn energy_at(&self, level: f64) -> f64 {
+        let level  = level.max(0.0).min(100.0);
+        let index  = (level as usize).min(99);
+        let offset = level - index as f64;
+
+        self.energy[index] + (self.energy[index + 1] - self.energy[index]) * offset
+    }
+
+    /// Returns the reported level at which a given percent of usable energy is left
+    pub fn level_at_energy(&self, energy: f64) -> f64 {
+        let energy = energy.max(0.0).min(100.0);
+        let index  = match self.energy.iter().position(|&value| value >= energy) {
+            Some(0)     => return 0.0,
+            Some(index) => index - 1,
+            None        => return 100.0,
+        };
+
+        let span = self.energy[index + 1] - self.energy[index];
+        index as f64 + if span > 0.0 { (energy - self.energy[index]) / span } else { 0.0 }
+    }
+
+    /// Returns the percent of energy used per percent of reported level around `level`
+    pub fn slope_at(&self, level: f64) -> f64 {
+        let index = (level.max(0.0) as usize).min(99);
+
+        self.energy[index + 1] - self.energy[index]
+    }
+}
+
+/// Splits a history into runs on battery with no sleep in between
+fn discharge_cycles(states: &[BatteryState]) -> Vec<&[BatteryState]> {
+    let mut cycles = Vec::new();
+    let mut start  = 0;
+
+    for index in 0..(states.len() + 1) {
+        let breaks = match states.get(index) {
+            None        => true,
+            Some(state) => {
+                let gap = if index > start {
+                    state.get_timestamp() as i64 - states[index - 1].get_timestamp() as i64
+                } else {
+                    0
+                };
+
+                state.get_charging() || gap < 0 || gap > MAX_SAMPLE_GAP
+            },
+        };
+
+        if breaks {
+            if index > start + 1 {
+                cycles.push(&states[start..index]);
+            }
+
+            start = if states.get(index).map_or(false, |state| state.get_charging()) { index + 1 } else { index };
+        }
+    }
+
+    cycles
+}
@@ -0,0 +1,256 @@
+extern crate toml;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    /// Name of this machine, used for storage keys and topics
+    #[serde(default = "default_device")]
+    pub device: String,
+
+    /// Level the charger stops at, if the firmware is set to limit charging
+    #[serde(default = "default_charge_limit")]
+    pub charge_limit: f64,
+
+    #[serde(default)]
+    pub estimator: EstimatorConfig,
+
+    #[serde(default)]
+    pub graph: GraphConfig,
+
+    pub mqtt: Option<MqttConfig>,
+
+    pub notifications: Option<NotificationConfig>,
+
+    /// Commands to run when the level or charging status changes
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+
+    /// How far the level has to climb back above a hook's level before it can fire again
+    #[serde(default = "default_hook_hysteresis")]
+    pub hook_hysteresis: f32,
+
+    /// Days of history `prune` keeps. The usage profile learns every hour of the week from
+    /// it, so it wants a few weeks at least.
+    #[serde(default = "default_history_days")]
+    pub history_days: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct GraphConfig {
+    /// Gaps longer than this many sampling intervals are drawn as the machine being asleep or off
+    #[serde(default = "default_gap_factor")]
+    pub gap_factor: f64,
+
+    /// Levels to draw a horizontal line at, e.g. where the battery counts as low
+    #[serde(default)]
+    pub reference_lines: Vec<ReferenceLine>,
+
+    /// Mark plugging in, unplugging, reaching full, notifications and waking up on the graph
+    #[serde(default = "default_markers")]
+    pub markers: bool,
+
+    /// Line colours to use over the theme's `series`, leaving out any to keep
+    #[serde(default)]
+    pub colors: SeriesColorOverrides,
+}
+
+impl Default for GraphConfig {
+    fn default() -> GraphConfig {
+        GraphConfig {
+            gap_factor:      default_gap_factor(),
+            reference_lines: Vec::new(),
+            markers:         default_markers(),
+            colors:          SeriesColorOverrides::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ReferenceLine {
+    pub level: f64,
+
+    #[serde(default)]
+    pub label: String,
+}
+
+/// Red, green and blue, each from 0 to 1
+pub type Color = (f64, f64, f64);
+
+/// Line colours for the different things the battery can be doing
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SeriesColors {
+    pub discharging: Color,
+    pub charging: Color,
+
+    /// Plugged in, but full or held at the charge limit
+    pub idle: Color,
+}
+
+/// Any of the series colours, for `[graph.colors]`
+#[derive(Deserialize, Default, Clone)]
+pub struct SeriesColorOverrides {
+    pub discharging: Option<Color>,
+    pub charging:    Option<Color>,
+    pub idle:        Option<Color>,
+}
+
+impl SeriesColorOverrides {
+    pub fn apply(&self, colors: &mut SeriesColors) {
+        colors.discharging = self.discharging.unwrap_or(colors.discharging);
+        colors.charging    = self.charging.unwrap_or(colors.charging);
+        colors.idle        = self.idle.unwrap_or(colors.idle);
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HookConfig {
+    /// Run when the level drops to this while discharging
+    pub level: Option<f32>,
+
+    /// Run when this happens instead
+    pub event: Option<HookEvent>,
+
+    /// Shell command to run
+    pub command: String,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    Plug,
+    Unplug,
+    Full,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct NotificationConfig {
+    /// Minutes remaining at which to warn, once each per discharge
+    #[serde(default = "default_notification_thresholds")]
+    pub thresholds: Vec<u32>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+pub struct EstimatorConfig {
+    #[serde(default)]
+    pub kind: EstimatorKind,
+
+    /// Seconds of history to look at, or the time constant for `ewma`
+    pub window: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EstimatorKind {
+    /// Slope between now and a sample a few minutes back
+    Slope,
+
+    /// Least-squares fit over the window
+    Regression,
+
+    /// Exponentially weighted average of the rate
+    Ewma,
+}
+
+impl Default for EstimatorKind {
+    fn default() -> EstimatorKind {
+        EstimatorKind::Slope
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MqttConfig {
+    /// Broker address, e.g. mqtt://localhost:1883
+    pub url: String,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    /// Prefix for the per-device state topics
+    #[serde(default = "default_mqtt_prefix")]
+    pub prefix: String,
+
+    /// Prefix Home Assistant listens on for discovery messages
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_device() -> String {
+    "surface".to_string()
+}
+
+fn default_charge_limit() -> f64 {
+    100.0
+}
+
+fn default_gap_factor() -> f64 {
+    3.0
+}
+
+fn default_markers() -> bool {
+    true
+}
+
+fn default_notification_thresholds() -> Vec<u32> {
+    vec![30, 15, 5]
+}
+
+fn default_hook_hysteresis() -> f32 {
+    2.0
+}
+
+fn default_history_days() -> u32 {
+    28
+}
+
+fn default_mqtt_prefix() -> String {
+    "priv-battery".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            device:          default_device(),
+            charge_limit:    default_charge_limit(),
+            estimator:       EstimatorConfig::default(),
+            graph:           GraphConfig::default(),
+            mqtt:            None,
+            notifications:   None,
+            hooks:           Vec::new(),
+            hook_hysteresis: default_hook_hysteresis(),
+            history_days:    default_history_days(),
+        }
+    }
+}
+
+/// Returns the directory our configuration files live in
+pub fn config_dir() -> PathBuf {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None      => env::home_dir().expect("Couldn't find a home directory.").join(".config"),
+    };
+
+    base.join("priv-battery")
+}
+
+/// Reads config.toml, falling back to defaults if it doesn't exist
+pub fn load() -> Config {
+    let path = config_dir().join("config.toml");
+
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_)   => return Config::default(),
+    };
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect("Couldn't read config.toml.");
+
+    toml::from_str(&contents).expect("Couldn't parse config.toml.")
+}
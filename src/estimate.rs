@@ -0,0 +1,532 @@
+use calibration::DischargeCurve;
+use config::{Config, EstimatorConfig, EstimatorKind};
+use graph::BatteryState;
+use std::rc::Rc;
+
+/// Level above which chargers switch to constant voltage and start slowing down
+const CV_LEVEL: f64 = 80.0;
+
+/// Fraction of the constant-current rate that's left once the battery is full
+const CV_END_FACTOR: f64 = 0.2;
+
+/// How much the charge rate drops per percent above `CV_LEVEL`
+const CV_SLOPE: f64 = (1.0 - CV_END_FACTOR) / (100.0 - CV_LEVEL);
+
+/// Typical noise on a reported level, in percent
+const LEVEL_NOISE: f64 = 0.5;
+
+/// Slowest rate a bound is allowed to settle on, in percent per second
+const MIN_BOUND_RATE: f64 = 1e-9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Charging,
+    Discharging,
+    Idle,
+}
+
+/// What an estimator thinks the battery is doing
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    /// Change in level, in percent per second
+    pub rate: f64,
+
+    /// Standard deviation of `rate`
+    pub rate_error: f64,
+
+    /// Seconds until the battery reaches `target`
+    pub remaining: f64,
+
+    /// How much to trust this estimate, from 0 to 1
+    pub confidence: f64,
+
+    pub direction: Direction,
+
+    /// Level at the time of the estimate
+    pub level: f64,
+
+    /// Level the battery is heading to: empty, or the charge limit while charging
+    pub target: f64,
+
+    pub charge_limit: f64,
+
+    /// How this device's reported level maps to energy, if it's been calibrated
+    pub curve: Option<Rc<DischargeCurve>>,
+}
+
+impl Estimate {
+    /// Builds an estimate from the current level and rate of change. Whether the battery is
+    /// charging comes from the device; the rate only tells charging from holding steady.
+    pub fn new(level: f64, rate: f64, rate_error: f64, confidence: f64, charging: bool, charge_limit: f64) -> Estimate {
+        let direction = match (charging, rate) {
+            (true, rate) if rate > 0.0  => Direction::Charging,
+            (false, rate) if rate < 0.0 => Direction::Discharging,
+            _                           => Direction::Idle,
+        };
+
+        let (target, remaining) = match direction {
+            Direction::Discharging => (0.0, level / -rate),
+            Direction::Charging    => {
+                let target = charge_limit.max(level);
+                (target, charge_time(level, target, rate / cv_factor(level)))
+            },
+            Direction::Idle        => (level, ::std::f64::INFINITY),
+        };
+
+        Estimate {
+            rate:         rate,
+            rate_error:   rate_error,
+            remaining:    remaining,
+            confidence:   confidence,
+            direction:    direction,
+            level:        level,
+            target:       target,
+            charge_limit: charge_limit,
+            curve:        None,
+        }
+    }
+
+    /// Redoes the time remaining along a discharge curve instead of a straight line to zero
+    pub fn with_curve(mut self, curve: Rc<DischargeCurve>) -> Estimate {
+        if self.direction == Direction::Discharging {
+            let energy_rate = -self.rate * curve.slope_at(self.level);
+            self.remaining  = curve.energy_at(self.level) / energy_rate;
+        }
+
+        self.curve = Some(curve);
+        self
+    }
+
+    /// Returns the estimates two standard deviations below and above this one. Neither goes
+    /// past a standstill into the opposite direction, however uncertain the rate is.
+    pub fn bounds(&self) -> (Estimate, Estimate) {
+        let spread = 2.0 * self.rate_error;
+        let bound  = |rate: f64| {
+            let rate = match self.direction {
+                Direction::Discharging => rate.min(-MIN_BOUND_RATE),
+                Direction::Charging    => rate.max(MIN_BOUND_RATE),
+                Direction::Idle        => 0.0,
+            };
+
+            let charging = self.direction == Direction::Charging;
+            let estimate = Estimate::new(self.level, rate, self.rate_error, self.confidence, charging, self.charge_limit);
+
+            match self.curve {
+                Some(ref curve) => estimate.with_curve(curve.clone()),
+                None            => estimate,
+            }
+        };
+
+        (bound(self.rate - spread), bound(self.rate + spread))
+    }
+
+    /// Predicts the level some number of seconds after the estimate was made
+    pub fn level_at(&self, seconds: f64) -> f64 {
+        match self.direction {
+            Direction::Discharging => match self.curve {
+                Some(ref curve) => {
+                    let energy_rate = -self.rate * curve.slope_at(self.level);
+                    curve.level_at_energy(curve.energy_at(self.level) - energy_rate * seconds)
+                },
+                None => (self.level + self.rate * seconds).max(0.0),
+            },
+            Direction::Charging    => charge_level(self.level, seconds, self.rate / cv_factor(self.level)).min(self.target),
+            Direction::Idle        => self.level,
+        }
+    }
+}
+
+/// How much of the constant-current rate is left while charging at `level`
+fn cv_factor(level: f64) -> f64 {
+    if level <= CV_LEVEL {
+        1.0
+    } else {
+        1.0 - CV_SLOPE * (level.min(100.0) - CV_LEVEL)
+    }
+}
+
+/// Seconds to charge from `from` to `to` at constant-current rate `cc_rate`
+fn charge_time(from: f64, to: f64, cc_rate: f64) -> f64 {
+    let linear = (to.min(CV_LEVEL) - from).max(0.0) / cc_rate;
+
+    // Above CV_LEVEL the rate falls off linearly with level, which integrates to a log
+    let tail = if to > CV_LEVEL {
+        let tail_from = from.max(CV_LEVEL);
+        (cv_factor(tail_from) / cv_factor(to)).ln() / (cc_rate * CV_SLOPE)
+    } else {
+        0.0
+    };
+
+    linear + tail
+}
+
+/// Level reached after charging for `seconds` from `from` at constant-current rate `cc_rate`
+fn charge_level(from: f64, seconds: f64, cc_rate: f64) -> f64 {
+    let linear_time = (CV_LEVEL - from).max(0.0) / cc_rate;
+
+    if seconds <= linear_time {
+        return from + cc_rate * seconds;
+    }
+
+    let tail_from   = from.max(CV_LEVEL);
+    let tail_factor = cv_factor(tail_from) * (-cc_rate * CV_SLOPE * (seconds - linear_time)).exp();
+
+    CV_LEVEL + (1.0 - tail_factor) / CV_SLOPE
+}
+
+pub trait Estimator {
+    /// Estimates time remaining from a history ordered oldest first
+    fn estimate(&self, states: &[BatteryState]) -> Option<Estimate>;
+
+    /// Seconds of history before the latest sample the estimate can depend on, so readers
+    /// only have to load that much before what they show
+    fn lookback(&self) -> u32;
+}
+
+/// Slope between the latest sample and the newest one at least `window` seconds older
+pub struct SlopeEstimator {
+    pub window: u32,
+    pub charge_limit: f64,
+}
+
+impl Estimator for SlopeEstimator {
+    fn estimate(&self, states: &[BatteryState]) -> Option<Estimate> {
+        let last_state = states.last()?;
+
+        // Timestamps can go backwards when the clock changes, so stay signed
+        let sample_threshold = last_state.get_timestamp() as i64 - self.window as i64;
+        let sample_index     = states.iter().rposition(|state| (state.get_timestamp() as i64) < sample_threshold)?;
+        let sample_state     = &states[sample_index];
+
+        let rate = {
+            let time  = (last_state.get_timestamp() as i64 - sample_state.get_timestamp() as i64) as f64;
+            let level = (last_state.get_level()            - sample_state.get_level()) as f64;
+
+            level / time
+        };
+
+        // Two points say little on their own, so lean on how many samples back them up
+        let samples_between = states.len() - sample_index;
+        let confidence      = (samples_between as f64 / 5.0).min(1.0);
+
+        // All we know about the error is how noisy the two levels themselves are
+        let time       = (last_state.get_timestamp() as i64 - sample_state.get_timestamp() as i64) as f64;
+        let rate_error = 2.0f64.sqrt() * LEVEL_NOISE / time;
+
+        Some(Estimate::new(last_state.get_level() as f64, rate, rate_error, confidence, last_state.get_charging(), self.charge_limit))
+    }
+
+    fn lookback(&self) -> u32 {
+        // Room for the sample from before the window, even after a short nap
+        self.window * 2
+    }
+}
+
+/// Least-squares fit over the samples in the last `window` seconds
+pub struct RegressionEstimator {
+    pub window: u32,
+    pub charge_limit: f64,
+}
+
+impl Estimator for RegressionEstimator {
+    fn estimate(&self, states: &[BatteryState]) -> Option<Estimate> {
+        let states     = current_run(states);
+        let last_state = states.last()?;
+        let last_time  = last_state.get_timestamp() as i64;
+
+        let points = states.iter()
+                           .map(|state| ((state.get_timestamp() as i64 - last_time) as f64, state.get_level() as f64))
+                           .filter(|&(time, _)| time <= 0.0 && time >= -(self.window as f64))
+                           .collect::<Vec<_>>();
+
+        if points.len() < 3 {
+            return None;
+        }
+
+        let count     = points.len() as f64;
+        let mean_time = points.iter().map(|&(time, _)| time).sum::<f64>() / count;
+        let mean_lvl  = points.iter().map(|&(_, level)| level).sum::<f64>() / count;
+
+        let sxx = points.iter().map(|&(time, _)| (time - mean_time).powi(2)).sum::<f64>();
+        let sxy = points.iter().map(|&(time, level)| (time - mean_time) * (level - mean_lvl)).sum::<f64>();
+
+        if sxx <= 0.0 {
+            return None;
+        }
+
+        let rate      = sxy / sxx;
+        let intercept = mean_lvl - rate * mean_time;
+
+        let residuals  = points.iter().map(|&(time, level)| (level - intercept - rate * time).powi(2)).sum::<f64>();
+        let rate_error = (residuals / (count - 2.0) / sxx).sqrt().max(LEVEL_NOISE / sxx.sqrt());
+
+        let level = intercept.max(0.0).min(100.0);
+
+        Some(Estimate::new(level, rate, rate_error, confidence_from_error(rate, rate_error), last_state.get_charging(), self.charge_limit))
+    }
+
+    fn lookback(&self) -> u32 {
+        self.window
+    }
+}
+
+/// Exponentially weighted average of the rate between consecutive samples
+pub struct EwmaEstimator {
+    /// Seconds after which a sample's weight has dropped to 1/e
+    pub time_constant: u32,
+    pub charge_limit: f64,
+}
+
+impl Estimator for EwmaEstimator {
+    fn estimate(&self, states: &[BatteryState]) -> Option<Estimate> {
+        let states     = current_run(states);
+        let last_state = states.last()?;
+
+        let mut mean     = None;
+        let mut variance = 0.0;
+        let mut weight   = 0.0;
+
+        for pair in states.windows(2) {
+            let time = (pair[1].get_timestamp() as i64 - pair[0].get_timestamp() as i64) as f64;
+            if time <= 0.0 {
+                continue;
+            }
+
+            let rate  = (pair[1].get_level() - pair[0].get_level()) as f64 / time;
+            let alpha = 1.0 - (-time / self.time_constant as f64).exp();
+
+            mean = Some(match mean {
+                None       => rate,
+                Some(mean) => {
+                    let delta = rate - mean;
+                    variance  = (1.0 - alpha) * (variance + alpha * delta * delta);
+                    mean + alpha * delta
+                },
+            });
+
+            // Effective number of samples the average is made of
+            weight = weight * (1.0 - alpha) + 1.0;
+        }
+
+        let rate = mean?;
+        if weight < 2.0 {
+            return None;
+        }
+
+        let rate_error = (variance / weight).sqrt();
+
+        Some(Estimate::new(last_state.get_level() as f64, rate, rate_error, confidence_from_error(rate, rate_error), last_state.get_charging(), self.charge_limit))
+    }
+
+    fn lookback(&self) -> u32 {
+        // Anything older carries under 1% of the weight
+        self.time_constant * 5
+    }
+}
+
+/// Returns the samples since the charger was last plugged in or out
+fn current_run(states: &[BatteryState]) -> &[BatteryState] {
+    let charging = match states.last() {
+        Some(state) => state.get_charging(),
+        None        => return states,
+    };
+
+    let start = states.iter()
+                      .rposition(|state| state.get_charging() != charging)
+                      .map(|index| index + 1)
+                      .unwrap_or(0);
+
+    &states[start..]
+}
+
+/// Turns a rate and its standard deviation into a confidence between 0 and 1
+fn confidence_from_error(rate: f64, rate_error: f64) -> f64 {
+    if rate == 0.0 {
+        return 0.0;
+    }
+
+    1.0 / (1.0 + rate_error / rate.abs())
+}
+
+/// Runs another estimator's results through a fitted discharge curve
+pub struct CalibratedEstimator {
+    pub inner: Box<Estimator>,
+    pub curve: Rc<DischargeCurve>,
+}
+
+impl Estimator for CalibratedEstimator {
+    fn estimate(&self, states: &[BatteryState]) -> Option<Estimate> {
+        self.inner.estimate(states).map(|estimate| estimate.with_curve(self.curve.clone()))
+    }
+
+    fn lookback(&self) -> u32 {
+        self.inner.lookback()
+    }
+}
+
+/// Returns the estimator the configuration asks for
+pub fn from_config(config: &Config) -> Box<Estimator> {
+    from_settings(&config.estimator, config.charge_limit)
+}
+
+/// The estimator `settings` pick, stopping at `charge_limit` when charging
+pub fn from_settings(settings: &EstimatorConfig, charge_limit: f64) -> Box<Estimator> {
+    match settings.kind {
+        EstimatorKind::Slope => Box::new(SlopeEstimator {
+            window:       settings.window.unwrap_or(60 * 5),
+            charge_limit: charge_limit,
+        }),
+        EstimatorKind::Regression => Box::new(RegressionEstimator {
+            window:       settings.window.unwrap_or(60 * 20),
+            charge_limit: charge_limit,
+        }),
+        EstimatorKind::Ewma => Box::new(EwmaEstimator {
+            time_constant: settings.window.unwrap_or(60 * 10),
+            charge_limit:  charge_limit,
+        }),
+    }
+}
+
+/// Formats a number of seconds as "Xh Ym"
+pub fn format_duration(seconds: f64) -> String {
+    let hours   = (seconds / 3600.0) as i32;
+    let minutes = (seconds / 60.0 % 60.0) as i32;
+
+    format!("{}h {}m", hours, minutes)
+}
+
+/// Describes an estimate the way the window and the CLI show it, e.g. "2h 10m left"
+pub fn describe(estimate: &Estimate) -> String {
+    match estimate.direction {
+        Direction::Discharging => format!("{} left", format_duration(estimate.remaining)),
+        Direction::Charging if estimate.remaining <= 0.0 => "Charged".to_string(),
+        Direction::Charging if estimate.target >= 100.0  => format!("{} until full", format_duration(estimate.remaining)),
+        Direction::Charging    => format!("{} until {:.0}%", format_duration(estimate.remaining), estimate.target),
+        Direction::Idle        => "Not charging".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fixtures::{run, state};
+    use super::*;
+
+    fn estimators() -> Vec<Box<Estimator>> {
+        vec![Box::new(SlopeEstimator      { window: 60 * 5,         charge_limit: 100.0 }),
+             Box::new(RegressionEstimator { window: 60 * 20,        charge_limit: 100.0 }),
+             Box::new(EwmaEstimator       { time_constant: 60 * 10, charge_limit: 100.0 })]
+    }
+
+    #[test]
+    fn empty_history_has_no_estimate() {
+        for estimator in estimators() {
+            assert!(estimator.estimate(&[]).is_none());
+        }
+    }
+
+    #[test]
+    fn single_sample_has_no_estimate() {
+        for estimator in estimators() {
+            assert!(estimator.estimate(&[state(1000, 50.0, false)]).is_none());
+        }
+    }
+
+    #[test]
+    fn slope_needs_a_sample_older_than_its_window() {
+        let estimator = SlopeEstimator { window: 60 * 5, charge_limit: 100.0 };
+
+        assert!(estimator.estimate(&run(1000, 80.0, -0.5, false, 5)).is_none());
+
+        let estimate = estimator.estimate(&run(1000, 80.0, -0.5, false, 7)).unwrap();
+        assert_eq!(estimate.direction, Direction::Discharging);
+        assert!((estimate.rate * 60.0 + 0.5).abs() < 1e-6);
+        assert!((estimate.remaining - 77.0 / 0.5 * 60.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn timestamps_going_backwards() {
+        // The clock was set back by an hour halfway through
+        let mut states = run(10000, 80.0, -0.5, false, 10);
+        states.extend(run(10000 + 600 - 3600, 75.0, -0.5, false, 3));
+
+        for estimator in estimators() {
+            if let Some(estimate) = estimator.estimate(&states) {
+                assert!(estimate.remaining >= 0.0);
+            }
+        }
+
+        // Nothing older than the window before the last sample, going by timestamps
+        assert!(SlopeEstimator { window: 60 * 5, charge_limit: 100.0 }.estimate(&states).is_none());
+
+        // Every sample newer than the last one
+        let backwards = vec![state(5000, 50.0, false), state(4000, 51.0, false)];
+        for estimator in estimators() {
+            assert!(estimator.estimate(&backwards).is_none());
+        }
+    }
+
+    #[test]
+    fn bounds_keep_the_direction() {
+        // So uncertain that two standard deviations either way cross zero
+        let discharging = Estimate::new(50.0, -0.001, 0.01, 0.5, false, 100.0);
+        let (low, high) = discharging.bounds();
+
+        assert_eq!((low.direction, high.direction), (Direction::Discharging, Direction::Discharging));
+        assert!(low.remaining <= discharging.remaining && discharging.remaining <= high.remaining);
+        assert!(high.level_at(3600.0) <= 50.0);
+
+        let charging    = Estimate::new(50.0, 0.001, 0.01, 0.5, true, 80.0);
+        let (low, high) = charging.bounds();
+
+        assert_eq!((low.direction, high.direction), (Direction::Charging, Direction::Charging));
+        assert!(low.level_at(3600.0) >= 50.0 && low.level_at(3600.0) <= 80.0);
+        assert!(high.level_at(3600.0) <= 80.0);
+    }
+
+    #[test]
+    fn flat_level_never_runs_out() {
+        let states   = run(1000, 60.0, 0.0, false, 30);
+        let estimate = SlopeEstimator { window: 60 * 5, charge_limit: 100.0 }.estimate(&states).unwrap();
+
+        assert_eq!(estimate.direction, Direction::Idle);
+        assert!(estimate.remaining.is_infinite());
+        assert_eq!(estimate.level_at(3600.0), 60.0);
+        assert_eq!(describe(&estimate), "Not charging");
+    }
+
+    #[test]
+    fn lookback_covers_what_the_estimate_needs() {
+        // Slowing down halfway, so anything looking further back than it says shows
+        let mut states = run(1000, 100.0, -0.5, false, 60);
+        states.extend(run(1000 + 3600, 70.0, -0.2, false, 60));
+
+        for estimator in estimators() {
+            let since  = states.last().unwrap().get_timestamp() - estimator.lookback();
+            let recent = states.iter().filter(|state| state.get_timestamp() >= since).cloned().collect::<Vec<_>>();
+
+            let all    = estimator.estimate(&states).unwrap();
+            let recent = estimator.estimate(&recent).unwrap();
+
+            assert!((all.rate - recent.rate).abs() <= all.rate.abs() * 0.02);
+        }
+    }
+
+    #[test]
+    fn direction_follows_the_charger() {
+        // Noise nudging the level up on battery doesn't make it charging
+        let mut states = run(1000, 60.0, -0.1, false, 10);
+        states.push(state(1000 + 60 * 10, 61.0, false));
+
+        let estimate = SlopeEstimator { window: 60 * 5, charge_limit: 100.0 }.estimate(&states).unwrap();
+        assert_eq!(estimate.direction, Direction::Idle);
+
+        // Plugged in but held at the charge limit, or losing ground to a weak charger
+        for &rate in &[0.0, -0.1] {
+            let states   = run(1000, 80.0, rate, true, 30);
+            let estimate = SlopeEstimator { window: 60 * 5, charge_limit: 80.0 }.estimate(&states).unwrap();
+
+            assert_eq!(estimate.direction, Direction::Idle);
+            assert!(estimate.remaining.is_infinite());
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use config::{Config, EstimatorConfig, NotificationConfig};
+use estimate;
+use graph::BatteryState;
+use hooks;
+use notify;
+
+/// Something worth marking on the graph
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Plug,
+    Unplug,
+    Full,
+
+    /// The estimate dropped below this many minutes left, which would have sent a notification
+    Threshold(u32),
+
+    /// The machine came back from being asleep or off
+    Resume,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    pub timestamp: i64,
+    pub kind:      EventKind,
+}
+
+/// The parts of the config that decide what counts as an event
+#[derive(Clone)]
+pub struct EventConfig {
+    pub gap_factor:    f64,
+    pub charge_limit:  f64,
+    pub notifications: Option<NotificationConfig>,
+
+    /// Notifications are replayed with the plain estimator, since a discharge curve fitted
+    /// later shouldn't move markers for what already happened
+    pub estimator: EstimatorConfig,
+}
+
+impl EventConfig {
+    pub fn from_config(config: &Config) -> EventConfig {
+        EventConfig {
+            gap_factor:    config.graph.gap_factor,
+            charge_limit:  config.charge_limit,
+            notifications: config.notifications.clone(),
+            estimator:     config.estimator.clone(),
+        }
+    }
+}
+
+/// Scans a history for events, in the order they happened
+pub fn find(config: &EventConfig, states: &[BatteryState]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let gaps       = find_gaps(states, config.gap_factor);
+    let estimator  = estimate::from_settings(&config.estimator, config.charge_limit);
+
+    // Replays notifications as they would have gone out at the time
+    let mut notified = Vec::new();
+
+    for index in 0..states.len() {
+        let state     = &states[index];
+        let timestamp = state.get_timestamp() as i64;
+
+        if gaps.contains(&index) {
+            events.push(Event { timestamp: timestamp, kind: EventKind::Resume });
+        }
+
+        if index > 0 {
+            let previous = &states[index - 1];
+
+            let kind = if state.get_charging() && !previous.get_charging() {
+                Some(EventKind::Plug)
+            } else if !state.get_charging() && previous.get_charging() {
+                Some(EventKind::Unplug)
+            } else if hooks::is_full(config.charge_limit, state) && !hooks::is_full(config.charge_limit, previous) {
+                Some(EventKind::Full)
+            } else {
+                None
+            };
+
+            if let Some(kind) = kind {
+                events.push(Event { timestamp: timestamp, kind: kind });
+            }
+        }
+
+        let notification_config = match config.notifications {
+            Some(ref notification_config) => notification_config,
+            None                          => continue,
+        };
+
+        // Estimating is the expensive part, and check ignores the estimate while charging
+        let charging = state.get_charging();
+        let estimate = if charging { None } else { estimator.estimate(&states[..(index + 1)]) };
+
+        let (remembered, urgency) = notify::check(notification_config, estimate.as_ref(), charging, &notified);
+
+        if urgency.is_some() {
+            let crossed = remembered.iter().filter(|&threshold| !notified.contains(threshold)).min().cloned();
+
+            if let Some(threshold) = crossed {
+                events.push(Event { timestamp: timestamp, kind: EventKind::Threshold(threshold) });
+            }
+        }
+
+        notified = remembered;
+    }
+
+    events
+}
+
+/// Returns the indices of samples that come after a gap much longer than the usual
+/// sampling interval, meaning the machine was asleep or off in between
+pub fn find_gaps(states: &[BatteryState], gap_factor: f64) -> Vec<usize> {
+    let mut intervals = states.windows(2)
+                              .map(|pair| pair[1].get_timestamp() as i64 - pair[0].get_timestamp() as i64)
+                              .filter(|&interval| interval > 0)
+                              .collect::<Vec<_>>();
+
+    if intervals.is_empty() {
+        return Vec::new();
+    }
+
+    intervals.sort();
+    let threshold = intervals[intervals.len() / 2] as f64 * gap_factor;
+
+    (1..states.len())
+        .filter(|&index| (states[index].get_timestamp() as i64 - states[index - 1].get_timestamp() as i64) as f64 > threshold)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fixtures::run;
+    use super::*;
+
+    fn config(notifications: Option<NotificationConfig>) -> EventConfig {
+        EventConfig { notifications: notifications, ..EventConfig::from_config(&Config::default()) }
+    }
+
+    fn kinds(events: &[Event]) -> Vec<EventKind> {
+        events.iter().map(|event| event.kind).collect()
+    }
+
+    #[test]
+    fn plugging_in_charging_full_and_unplugging() {
+        let mut states = run(1000, 95.0, -0.5, false, 5);
+        states.extend(run(1300, 93.0, 1.5, true, 6));
+        states.extend(run(1660, 100.0, 0.0, false, 3));
+
+        assert_eq!(kinds(&find(&config(None), &states)), vec![EventKind::Plug, EventKind::Full, EventKind::Unplug]);
+    }
+
+    #[test]
+    fn waking_up() {
+        let mut states = run(1000, 80.0, -0.5, false, 10);
+        states.extend(run(1000 + 3600, 75.0, -0.5, false, 10));
+
+        assert_eq!(kinds(&find(&config(None), &states)), vec![EventKind::Resume]);
+    }
+
+    #[test]
+    fn replays_notifications_once_each() {
+        // Half a percent a minute from 30% is under an hour left as soon as there's an
+        // estimate, and under twenty minutes after forty minutes or so
+        let states = run(1000, 30.0, -0.5, false, 45);
+        let found  = find(&config(Some(NotificationConfig { thresholds: vec![60, 20] })), &states);
+
+        assert_eq!(kinds(&found), vec![EventKind::Threshold(60), EventKind::Threshold(20)]);
+        assert!(found[0].timestamp < found[1].timestamp);
+    }
+
+    #[test]
+    fn plugging_in_rearms_notifications_without_gaining_charge() {
+        // Held at the same level on the charger for a while, then back on battery
+        let mut states = run(1000, 30.0, -0.5, false, 15);
+        states.extend(run(1000 + 60 * 15, 23.0, 0.0, true, 10));
+        states.extend(run(1000 + 60 * 25, 23.0, -0.5, false, 15));
+
+        let found      = find(&config(Some(NotificationConfig { thresholds: vec![60] })), &states);
+        let thresholds = found.iter().filter(|event| event.kind == EventKind::Threshold(60)).count();
+
+        assert_eq!(thresholds, 2);
+    }
+}
@@ -0,0 +1,19 @@
+//! Histories for the tests to run on
+
+use graph::BatteryState;
+
+pub fn state(timestamp: u32, level: f32, charging: bool) -> BatteryState {
+    let mut state = BatteryState::new();
+    state.set_timestamp(timestamp);
+    state.set_level(level);
+    state.set_charging(charging);
+
+    state
+}
+
+/// A sample a minute for `count` minutes, starting at `level` and changing by `rate`
+/// percent a minute
+pub fn run(start: u32, level: f32, rate: f32, charging: bool, count: usize) -> Vec<BatteryState> {
+    (0..count).map(|minute| state(start + minute as u32 * 60, level + rate * minute as f32, charging))
+              .collect()
+}
@@ -0,0 +1,194 @@
+use config::{Config, HookConfig, HookEvent};
+use estimate::Estimate;
+use graph::BatteryState;
+use std::process::Command;
+
+/// Runs the hooks a new sample triggers. `fired` holds the level hooks that already ran and
+/// haven't been re-armed yet; the updated set is returned to be kept for next time.
+pub fn run(config: &Config, states: &[BatteryState], estimate: Option<&Estimate>, fired: &[String]) -> Vec<String> {
+    let (hooks, still_fired) = triggered(config, states, fired);
+
+    if let Some(state) = states.last() {
+        let full = is_full(config.charge_limit, state);
+
+        for hook in hooks {
+            run_command(hook, state, full, estimate);
+        }
+    }
+
+    still_fired
+}
+
+/// Works out which hooks the latest sample sets off without running any of them. Returns
+/// those hooks and the level hooks to keep as fired.
+pub fn triggered<'a>(config: &'a Config, states: &[BatteryState], fired: &[String]) -> (Vec<&'a HookConfig>, Vec<String>) {
+    let state = match states.last() {
+        Some(state) => state,
+        None        => return (Vec::new(), fired.to_vec()),
+    };
+
+    let previous = if states.len() >= 2 { states.get(states.len() - 2) } else { None };
+    let level    = state.get_level();
+    let full     = is_full(config.charge_limit, state);
+
+    let mut hooks       = Vec::new();
+    let mut still_fired = Vec::new();
+
+    for hook in config.hooks.iter() {
+        let id = hook_id(hook);
+
+        let triggered = match (hook.level, hook.event) {
+            (Some(threshold), _) => {
+                let already_fired = fired.contains(&id);
+
+                // Only re-arm once the level has climbed back up past the hysteresis margin
+                let stays_fired = already_fired && level < threshold + config.hook_hysteresis;
+                let fires       = !already_fired && !state.get_charging() && level <= threshold;
+
+                if stays_fired || fires {
+                    still_fired.push(id);
+                }
+
+                fires
+            },
+            (None, Some(event)) => match (event, previous) {
+                (HookEvent::Plug,   Some(previous)) => state.get_charging() && !previous.get_charging(),
+                (HookEvent::Unplug, Some(previous)) => !state.get_charging() && previous.get_charging(),
+                (HookEvent::Full,   Some(previous)) => full && !is_full(config.charge_limit, previous),
+                (_, None)                           => false,
+            },
+            (None, None) => false,
+        };
+
+        if triggered {
+            hooks.push(hook);
+        }
+    }
+
+    (hooks, still_fired)
+}
+
+/// Plugged in and as charged as the charger will take it
+pub fn is_full(charge_limit: f64, state: &BatteryState) -> bool {
+    state.get_charging() && state.get_level() as f64 >= charge_limit - 0.5
+}
+
+/// Identifies a level hook across runs, even when others are added or removed around it
+fn hook_id(hook: &HookConfig) -> String {
+    format!("{}:{}", hook.level.unwrap_or(0.0), hook.command)
+}
+
+fn run_command(hook: &HookConfig, state: &BatteryState, full: bool, estimate: Option<&Estimate>) {
+    let status = match (state.get_charging(), full) {
+        (_, true)     => "full",
+        (true, false) => "charging",
+        (false, _)    => "discharging",
+    };
+
+    let remaining = estimate.map(|estimate| estimate.remaining)
+                            .filter(|remaining| remaining.is_finite())
+                            .map_or(String::new(), |remaining| format!("{:.0}", remaining));
+
+    // Don't wait around, the command may well be suspending this machine
+    let spawned = Command::new("sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .env("BATTERY_LEVEL",     format!("{:.1}", state.get_level()))
+        .env("BATTERY_STATUS",    status)
+        .env("BATTERY_REMAINING", remaining)
+        .spawn();
+
+    // The other hooks still get their turn, and what fired still gets saved
+    if let Err(error) = spawned {
+        eprintln!("Couldn't run hook command \"{}\": {}", hook.command, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fixtures::state;
+    use super::*;
+
+    fn config(hooks: Vec<HookConfig>) -> Config {
+        Config { hooks: hooks, charge_limit: 80.0, ..Config::default() }
+    }
+
+    fn at_level(level: f32, command: &str) -> HookConfig {
+        HookConfig { level: Some(level), event: None, command: command.to_string() }
+    }
+
+    fn on(event: HookEvent, command: &str) -> HookConfig {
+        HookConfig { level: None, event: Some(event), command: command.to_string() }
+    }
+
+    /// Feeds the samples through one at a time, like logging does, keeping what fired in between
+    fn replay(config: &Config, states: &[BatteryState]) -> Vec<Vec<String>> {
+        let mut fired = Vec::new();
+
+        (1..(states.len() + 1)).map(|end| {
+            let (hooks, still_fired) = triggered(config, &states[..end], &fired);
+            fired = still_fired;
+
+            hooks.iter().map(|hook| hook.command.clone()).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn level_hooks_fire_once_until_rearmed() {
+        let config = config(vec![at_level(10.0, "suspend")]);
+        let states = [
+            state(1000, 11.0, false),
+            state(1060, 10.0, false),
+            state(1120,  9.0, false),
+            // Charging back up, but not past the hysteresis margin yet
+            state(1180, 11.0, true),
+            state(1240, 10.0, false),
+            // Past it, so the next drop fires again
+            state(1300, 12.0, true),
+            state(1360, 10.0, false),
+        ];
+
+        let ran = replay(&config, &states).into_iter().map(|commands| commands.len()).collect::<Vec<_>>();
+        assert_eq!(ran, vec![0, 1, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn fired_hooks_carry_over_between_runs() {
+        let config = config(vec![at_level(20.0, "dim"), at_level(10.0, "suspend")]);
+        let states = [state(1000, 21.0, false), state(1060, 15.0, false)];
+
+        let (hooks, fired) = triggered(&config, &states, &[]);
+        assert_eq!(hooks.len(), 1);
+        assert_eq!(fired, vec![hook_id(&config.hooks[0])]);
+
+        // What was saved last time stops it firing again from a fresh process
+        let (hooks, fired) = triggered(&config, &states, &fired);
+        assert!(hooks.is_empty());
+        assert_eq!(fired, vec![hook_id(&config.hooks[0])]);
+
+        // Nothing to go on keeps everything as it was
+        let (hooks, kept) = triggered(&config, &[], &fired);
+        assert!(hooks.is_empty());
+        assert_eq!(kept, fired);
+    }
+
+    #[test]
+    fn event_hooks() {
+        let config = config(vec![on(HookEvent::Plug, "plug"), on(HookEvent::Unplug, "unplug"), on(HookEvent::Full, "full")]);
+        let states = [
+            state(1000, 70.0, false),
+            state(1060, 71.0, true),
+            state(1120, 80.0, true),
+            state(1180, 80.0, true),
+            state(1240, 79.0, false),
+        ];
+
+        assert_eq!(replay(&config, &states), vec![
+            vec![],
+            vec!["plug".to_string()],
+            vec!["full".to_string()],
+            vec![],
+            vec!["unplug".to_string()],
+        ]);
+    }
+}
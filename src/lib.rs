@@ -0,0 +1,23 @@
+extern crate gtk;
+extern crate gdk;
+extern crate protobuf;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+
+pub mod backtest;
+pub mod calibration;
+pub mod config;
+pub mod estimate;
+pub mod events;
+#[cfg(test)] mod fixtures;
+pub mod graph;
+pub mod hooks;
+pub mod linegraph;
+pub mod mqtt;
+pub mod notify;
+pub mod plot;
+pub mod profile;
+pub mod render;
+pub mod stats;
+pub mod theme;
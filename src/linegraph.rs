@@ -1,148 +1,1263 @@
 extern crate gtk;
+extern crate gdk;
 extern crate cairo;
+extern crate chrono;
 
+use config::{Color, GraphConfig, ReferenceLine, SeriesColors};
+use estimate::{self, Direction, Estimate, Estimator};
+use events::{self, Event, EventConfig, EventKind};
 use graph::{BatteryGraph, BatteryState};
 use gtk::prelude::*;
 use self::cairo::Gradient;
+use self::chrono::{Local, TimeZone};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use theme::Theme;
 
-pub fn create_linegraph(graph: BatteryGraph) -> gtk::DrawingArea {
-    let widget = gtk::DrawingArea::new();
-    widget.set_size_request(-1, 100);
-    widget.set_hexpand(true);
-    widget.set_vexpand(true);
+/// The stretch of time the graph shows. Either end left out follows the data: the first
+/// sample on the left, and the end of the projection on the right.
+#[derive(Clone, Copy, Default)]
+pub struct View {
+    pub start: Option<i64>,
+    pub end:   Option<i64>,
+}
+
+impl View {
+    /// Shows the last `seconds` of history, or everything if `None`
+    pub fn last(seconds: Option<i64>, now: i64) -> View {
+        View {
+            start: seconds.map(|seconds| now - seconds),
+            end:   None,
+        }
+    }
+}
+
+/// Where the last draw put the graph, for turning pointer positions back into times
+#[derive(Clone, Copy)]
+pub struct Shown {
+    left:  f64,
+    width: f64,
+    start: i64,
+    end:   i64,
+}
+
+impl Shown {
+    fn time_at(&self, x: f64) -> i64 {
+        self.start + ((x - self.left) / self.width * (self.end - self.start) as f64) as i64
+    }
+}
+
+/// What the graph shows that's worked out from the history rather than drawn straight from
+/// it. That takes passes over all of it, so it's redone when the samples change, not per draw.
+pub struct Analysis {
+    /// Where the battery is heading, if there's enough history to say
+    pub estimate: Option<Estimate>,
+
+    /// Where it would be heading going by usual use at this time of the week
+    pub usual: Option<Estimate>,
+
+    /// What happened along the way, to mark on the graph
+    pub events: Vec<Event>,
+
+    /// Indices of the samples the machine woke up at
+    pub gaps: Vec<usize>,
+}
+
+impl Analysis {
+    /// `usual` estimates from how the battery usually gets used, which takes more history
+    /// than gets loaded to draw, so it's learned elsewhere
+    pub fn new(states: &[BatteryState], estimator: &Estimator, usual: &Estimator, config: &EventConfig) -> Analysis {
+        // Only project forward when there's enough history to say something sensible
+        Analysis {
+            estimate: estimator.estimate(states),
+            usual:    usual.estimate(states),
+            events:   events::find(config, states),
+            gaps:     events::find_gaps(states, config.gap_factor),
+        }
+    }
+}
+
+/// The samples from `since` on out of a history in time order, or all of them without it
+pub fn states_since(states: &[BatteryState], since: Option<i64>) -> &[BatteryState] {
+    let start = since.map_or(0, |since| {
+        states.iter().position(|state| state.get_timestamp() as i64 >= since).unwrap_or(states.len())
+    });
+
+    &states[start..]
+}
 
-    widget.connect_draw(move |widget, context| {
-        let width  = widget.get_allocated_width()  as f64;
-        let height = widget.get_allocated_height() as f64;
+/// Another device's history, drawn as a plain line alongside ours
+pub struct Overlay {
+    pub device: String,
+    pub color:  Color,
+    pub graph:  BatteryGraph,
+}
+
+/// A widget drawing a battery history, which the window here or any other GTK tool can
+/// embed and keep feeding samples. Clones share the same widget and data.
+#[derive(Clone)]
+pub struct LineGraph {
+    widget:    gtk::DrawingArea,
+    graph:     Rc<RefCell<BatteryGraph>>,
+    overlays:  Rc<RefCell<Vec<Overlay>>>,
+    view:      Rc<RefCell<View>>,
+    estimator: Rc<RefCell<Box<Estimator>>>,
+    usual:     Rc<Box<Estimator>>,
+    events:    EventConfig,
+    analysis:  Rc<RefCell<Analysis>>,
+    theme:     Rc<RefCell<Theme>>,
+}
+
+impl LineGraph {
+    /// Creates a graph with no samples yet, showing all of them once there are. `usual` is
+    /// what the estimate gets compared with, and `events` is what finding gaps and markers in
+    /// the history needs of the rest of the config.
+    pub fn new(estimator: Box<Estimator>, usual: Box<Estimator>, config: GraphConfig, events: EventConfig, theme: Theme) -> LineGraph {
+        let graph     = Rc::new(RefCell::new(BatteryGraph::new()));
+        let overlays  = Rc::new(RefCell::new(Vec::new()));
+        let view      = Rc::new(RefCell::new(View::default()));
+        let analysis  = Rc::new(RefCell::new(Analysis::new(&[], &*estimator, &*usual, &events)));
+        let estimator = Rc::new(RefCell::new(estimator));
+        let usual     = Rc::new(usual);
+        let theme     = Rc::new(RefCell::new(theme));
+
+        let widget = gtk::DrawingArea::new();
+        widget.set_size_request(-1, 100);
+        widget.set_hexpand(true);
+        widget.set_vexpand(true);
+
+        // Where the pointer is, if it's over the widget
+        let hover: Rc<Cell<Option<f64>>> = Rc::new(Cell::new(None));
 
-        let graph_left   = 70.0;
-        let graph_top    = 50.0;
-        let graph_right  = width - 20.0;
-        let graph_bottom = height - 80.0;
-        let graph_width  = graph_right - graph_left;
-        let graph_height = graph_bottom - graph_top;
+        // Pointer position and shown range a drag started at
+        let drag: Rc<Cell<Option<(f64, Shown)>>> = Rc::new(Cell::new(None));
+
+        let shown: Rc<Cell<Option<Shown>>> = Rc::new(Cell::new(None));
+
+        widget.add_events((gdk::POINTER_MOTION_MASK | gdk::LEAVE_NOTIFY_MASK | gdk::SCROLL_MASK |
+                           gdk::BUTTON_PRESS_MASK | gdk::BUTTON_RELEASE_MASK).bits() as i32);
 
         {
-            context.set_line_width(1.0);
-            context.set_line_cap(cairo::LineCap::Butt);
-            context.set_source_rgba(1.0, 1.0, 1.0, 0.2);
-            context.translate(0.5, 0.5);
+            let (hover, drag, view) = (hover.clone(), drag.clone(), view.clone());
+            widget.connect_motion_notify_event(move |widget, event| {
+                let (x, _) = event.get_position();
+                hover.set(Some(x));
 
-            context.move_to(graph_left, graph_top);
-            context.line_to(graph_right, graph_top);
+                if let Some((start_x, start_shown)) = drag.get() {
+                    let shift = start_shown.time_at(x) - start_shown.time_at(start_x);
 
-            context.move_to(graph_left, graph_top + graph_height / 2.0);
-            context.line_to(graph_right, graph_top + graph_height / 2.0);
+                    *view.borrow_mut() = View {
+                        start: Some(start_shown.start - shift),
+                        end:   Some(start_shown.end - shift),
+                    };
+                }
 
-            context.move_to(graph_left, graph_bottom);
-            context.line_to(graph_right, graph_bottom);
+                widget.queue_draw();
 
-            context.stroke();
-            context.translate(-0.5, -0.5);
-
-            context.set_font_size(16.0);
-            context.select_font_face("Droid Sans",
-                                     cairo::enums::FontSlant::Normal,
-                                     cairo::enums::FontWeight::Normal);
-
-            context.set_source_rgba(1.0, 1.0, 1.0, 0.3);
-            for &(text, y) in [ ("100%", graph_top),
-                               ("50%", graph_top + graph_height / 2.0),
-                               ("0%", graph_bottom) ].iter() {
-                let extents = context.text_extents(text);
-                let x = graph_left - 10.0 - extents.width - extents.x_bearing;
-                let y = y - extents.height / 2.0 - extents.y_bearing;
-
-                context.move_to(x, y);
-                context.show_text(text);
+                Inhibit(false)
+            });
+        }
+
+        {
+            let (drag, shown) = (drag.clone(), shown.clone());
+            widget.connect_button_press_event(move |_, event| {
+                if let (1, Some(current)) = (event.get_button(), shown.get()) {
+                    drag.set(Some((event.get_position().0, current)));
+                }
+
+                Inhibit(false)
+            });
+        }
+
+        {
+            let drag = drag.clone();
+            widget.connect_button_release_event(move |_, _| {
+                drag.set(None);
+
+                Inhibit(false)
+            });
+        }
+
+        {
+            let (shown, view) = (shown.clone(), view.clone());
+            widget.connect_scroll_event(move |widget, event| {
+                let current = match shown.get() {
+                    Some(current) => current,
+                    None          => return Inhibit(false),
+                };
+
+                let factor = match event.get_direction() {
+                    gdk::ScrollDirection::Up   => 0.8,
+                    gdk::ScrollDirection::Down => 1.25,
+                    _                          => return Inhibit(false),
+                };
+
+                // Zoom around the time under the pointer, so it stays put
+                let anchor = current.time_at(event.get_position().0);
+                let start  = anchor - ((anchor - current.start) as f64 * factor) as i64;
+                let end    = anchor + ((current.end - anchor) as f64 * factor) as i64;
+
+                if end - start > 60 {
+                    *view.borrow_mut() = View { start: Some(start), end: Some(end) };
+                    widget.queue_draw();
+                }
+
+                Inhibit(true)
+            });
+        }
+
+        {
+            let hover = hover.clone();
+            widget.connect_leave_notify_event(move |widget, _| {
+                hover.set(None);
+                widget.queue_draw();
+
+                Inhibit(false)
+            });
+        }
+
+        {
+            let (graph, overlays, view, analysis, theme) = (graph.clone(), overlays.clone(), view.clone(), analysis.clone(), theme.clone());
+            widget.connect_draw(move |widget, context| {
+                let width  = widget.get_allocated_width()  as f64;
+                let height = widget.get_allocated_height() as f64;
+                let scale  = widget.get_scale_factor() as f64;
+
+                let graph = graph.borrow();
+                shown.set(draw_graph(context, width, height, scale, graph.get_states(), &analysis.borrow(), &overlays.borrow(),
+                                     &view.borrow(), &config, &theme.borrow(), hover.get()));
+
+                Inhibit(false)
+            });
+        }
+
+        LineGraph {
+            widget:    widget,
+            graph:     graph,
+            overlays:  overlays,
+            view:      view,
+            estimator: estimator,
+            usual:     usual,
+            events:    events,
+            analysis:  analysis,
+            theme:     theme,
+        }
+    }
+
+    pub fn widget(&self) -> &gtk::DrawingArea {
+        &self.widget
+    }
+
+    /// Replaces the whole history
+    pub fn set_graph(&self, graph: BatteryGraph) {
+        *self.graph.borrow_mut() = graph;
+        self.analyse();
+    }
+
+    /// Adds samples newer than the ones already shown. Any that aren't, say from the history
+    /// having been loaded again since they arrived, are left out.
+    pub fn append_states(&self, states: &[BatteryState]) {
+        {
+            let mut graph = self.graph.borrow_mut();
+
+            for state in states {
+                let last = graph.get_states().last().map(|last| last.get_timestamp());
+
+                if last.map_or(true, |last| state.get_timestamp() > last) {
+                    graph.mut_states().push(state.clone());
+                }
             }
         }
 
-        let states = graph.get_states();
-        draw_graph_line((graph_left, graph_top, graph_width, graph_height), states, &context);
+        self.analyse();
+    }
+
+    pub fn last_state(&self) -> Option<BatteryState> {
+        self.graph.borrow().get_states().last().cloned()
+    }
 
-        Inhibit(false)
-    });
+    /// Samples from `since` on, or the whole history without it
+    pub fn states_since(&self, since: Option<i64>) -> Vec<BatteryState> {
+        states_since(self.graph.borrow().get_states(), since).to_vec()
+    }
 
-    widget
-}
+    /// Replaces the other devices drawn alongside this one
+    pub fn set_overlays(&self, overlays: Vec<Overlay>) {
+        *self.overlays.borrow_mut() = overlays;
+        self.queue_draw();
+    }
 
-fn draw_graph_line((x, y, width, height): (f64, f64, f64, f64), states: &[BatteryState], context: &cairo::Context) {
-    context.translate(x, y);
+    /// Adds samples to another device's history, if it's one being shown. Any that aren't
+    /// newer than what it has already, say from loading it again, are left out.
+    pub fn append_overlay_states(&self, device: &str, states: &[BatteryState]) {
+        {
+            let mut overlays = self.overlays.borrow_mut();
 
-    let num_states   = states.len();
-    let first_state  = states.first().unwrap();
-    let last_state   = states.last().unwrap();
+            let overlay = match overlays.iter_mut().find(|overlay| overlay.device == device) {
+                Some(overlay) => overlay,
+                None          => return,
+            };
 
-    let sample_threshold = last_state.get_timestamp() - (60 * 5);
-    let sample_state     = states.iter().rev().find(|state| state.get_timestamp() < sample_threshold).unwrap();
+            for state in states {
+                let last = overlay.graph.get_states().last().map(|last| last.get_timestamp());
 
-    let estimated_ratio = {
-        let time  = (last_state.get_timestamp() - sample_state.get_timestamp()) as f64;
-        let level = (last_state.get_level()     - sample_state.get_level()) as f64;
+                if last.map_or(true, |last| state.get_timestamp() > last) {
+                    overlay.graph.mut_states().push(state.clone());
+                }
+            }
+        }
 
-        level / time
-    };
+        self.queue_draw();
+    }
 
-    let estimated_time = last_state.get_level() as f64 / estimated_ratio.abs();
+    /// Shows a different stretch of time. Dragging and scrolling carry on from here.
+    pub fn set_view(&self, view: View) {
+        *self.view.borrow_mut() = view;
+        self.queue_draw();
+    }
 
-    {
-        let estimated_text = {
-            let hours   = (estimated_time / 3600.0) as i32;
-            let minutes = (estimated_time / 60.0 % 60.0) as i32;
+    pub fn set_estimator(&self, estimator: Box<Estimator>) {
+        *self.estimator.borrow_mut() = estimator;
+        self.analyse();
+    }
 
-            &format!("{}h {}m left", hours, minutes)
-        };
+    pub fn set_theme(&self, theme: Theme) {
+        *self.theme.borrow_mut() = theme;
+        self.queue_draw();
+    }
+
+    pub fn queue_draw(&self) {
+        self.widget.queue_draw();
+    }
+
+    /// Works the estimate and the like out again after the history or estimator changed
+    fn analyse(&self) {
+        *self.analysis.borrow_mut() = Analysis::new(self.graph.borrow().get_states(), &**self.estimator.borrow(), &**self.usual, &self.events);
+        self.queue_draw();
+    }
+}
+
+/// Graphs squeezed shorter than this give up the labels around them, one row at a time
+const MIN_GRAPH_HEIGHT: f64 = 40.0;
 
-        context.set_source_rgba(1.0, 1.0, 1.0, 0.3);
-        context.set_font_size(16.0);
-        context.select_font_face("Droid Sans",
+/// Length of the ticks under the time axis
+const TICK_LENGTH: f64 = 5.0;
+
+/// Where the graph and the labels around it go, worked out from how big the text comes out
+struct Layout {
+    /// Left, top, width and height of the graph itself
+    graph: (f64, f64, f64, f64),
+
+    /// Levels labelled along the left edge
+    levels: Vec<f64>,
+
+    /// Centres of the rows of labels that fit, relative to the top of the graph
+    legend:   Option<f64>,
+    now:      Option<f64>,
+    axis:     Option<f64>,
+    estimate: Option<f64>,
+
+    /// Device pixels per unit, for keeping thin lines sharp
+    scale: f64,
+}
+
+impl Layout {
+    fn new(context: &cairo::Context, width: f64, height: f64, scale: f64, theme: &Theme) -> Layout {
+        let margins = &theme.margins;
+
+        context.select_font_face(&theme.font.family,
                                  cairo::enums::FontSlant::Normal,
                                  cairo::enums::FontWeight::Normal);
 
-        context.set_source_rgba(1.0, 1.0, 1.0, 0.3);
+        context.set_font_size(theme.font.small_size);
+        let small_row = context.font_extents().height * 1.5;
+
+        context.set_font_size(theme.font.size);
+        let large_row   = context.font_extents().height * 1.5;
+        let level_width = context.text_extents("100%").x_advance + 10.0;
+
+        let row_height = |shown: bool, size: f64| if shown { size } else { 0.0 };
+
+        // Give up the estimate, then the legend, then "now", then the times, until the graph fits
+        let mut dropped = 0;
+        let (legend, now, axis, estimate, top, bottom) = loop {
+            let (legend, now, axis, estimate) = (dropped < 2, dropped < 3, dropped < 4, dropped < 1);
+
+            let top    = margins.top + row_height(legend, small_row) + row_height(now, small_row);
+            let bottom = margins.bottom + row_height(axis, TICK_LENGTH + small_row) + row_height(estimate, large_row);
+
+            if height - top - bottom >= MIN_GRAPH_HEIGHT || dropped == 4 {
+                break (legend, now, axis, estimate, top, bottom);
+            }
+
+            dropped += 1;
+        };
+
+        let graph_height = (height - top - bottom).max(0.0);
+
+        let left        = margins.left + level_width;
+        let graph_width = (width - left - margins.right).max(0.0);
+
+        // Level labels sit on their gridlines, so they need a row's worth of room between them
+        let levels = if graph_height >= 2.0 * large_row {
+            vec![100.0, 50.0, 0.0]
+        } else if graph_height >= large_row {
+            vec![100.0, 0.0]
+        } else {
+            Vec::new()
+        };
+
+        let axis_height = row_height(axis, TICK_LENGTH + small_row);
+
+        Layout {
+            graph:    (left, top, graph_width, graph_height),
+            levels:   levels,
+            legend:   if legend   { Some(-row_height(now, small_row) - small_row / 2.0) } else { None },
+            now:      if now      { Some(-small_row / 2.0) } else { None },
+            axis:     if axis     { Some(graph_height + TICK_LENGTH + small_row / 2.0) } else { None },
+            estimate: if estimate { Some(graph_height + axis_height + large_row / 2.0) } else { None },
+            scale:    scale,
+        }
+    }
+}
+
+/// Moves a line of the given width onto whole device pixels, so it comes out sharp at any scale
+fn snap(position: f64, line_width: f64, scale: f64) -> f64 {
+    let device_width = (line_width * scale).round().max(1.0);
+    let offset       = if device_width % 2.0 == 1.0 { 0.5 } else { 0.0 };
+
+    ((position * scale).round() + offset) / scale
+}
+
+/// Draws the whole graph, gridlines and labels included, onto any cairo context. `analysis`
+/// has to be of `states`. `scale` is the number of device pixels per unit, and `hover` the
+/// pointer's x position, if any.
+pub fn draw_graph(context: &cairo::Context, width: f64, height: f64, scale: f64, states: &[BatteryState], analysis: &Analysis,
+                  overlays: &[Overlay], view: &View, config: &GraphConfig, theme: &Theme, hover: Option<f64>) -> Option<Shown> {
+    let layout = Layout::new(context, width, height, scale, theme);
+
+    let (graph_left, graph_top, graph_width, graph_height) = layout.graph;
+    let graph_right = graph_left + graph_width;
+    let level_y     = |level: f64| snap(graph_top + graph_height - level / 100.0 * graph_height, theme.gridlines.width, scale);
+
+    {
+        context.set_line_width(theme.gridlines.width);
+        context.set_line_cap(cairo::LineCap::Butt);
+        context.set_dash(&theme.gridlines.dash, 0.0);
+        set_foreground(theme, theme.gridlines.opacity, context);
+
+        for &level in [100.0, 50.0, 0.0].iter() {
+            context.move_to(graph_left, level_y(level));
+            context.line_to(graph_right, level_y(level));
+        }
+
+        context.stroke();
+        context.set_dash(&[], 0.0);
+
+        context.set_font_size(theme.font.size);
+
+        set_foreground(theme, 0.3, context);
+        for &level in layout.levels.iter() {
+            let text    = &format!("{}%", level);
+            let extents = context.text_extents(text);
+            let x = graph_left - 10.0 - extents.width - extents.x_bearing;
+            let y = level_y(level) - extents.height / 2.0 - extents.y_bearing;
+
+            context.move_to(x, y);
+            context.show_text(text);
+        }
+    }
+
+    // Too small to draw anything sensible in
+    if graph_width < 1.0 || graph_height < 1.0 {
+        return None;
+    }
+
+    let hover = hover.map(|x| x - graph_left);
+    let range = draw_graph_line(&layout, states, analysis, overlays, view, config, theme, hover, context);
+
+    range.map(|(start, end)| Shown {
+        left:  graph_left,
+        width: graph_width,
+        start: start,
+        end:   end,
+    })
+}
+
+/// Draws the history and projection into the given area, returning the time range it covers
+fn draw_graph_line(layout: &Layout, states: &[BatteryState], analysis: &Analysis, overlays: &[Overlay], view: &View, config: &GraphConfig, theme: &Theme, hover: Option<f64>, context: &cairo::Context) -> Option<(i64, i64)> {
+    let colors = &theme.series;
+    let scale  = layout.scale;
+
+    let (x, y, width, height) = layout.graph;
+    context.translate(x, y);
+
+    context.set_font_size(theme.font.size);
+
+    let (first_state, last_state) = match (states.first(), states.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            draw_centered_text("Collecting data…", width, height, theme, context);
+            context.translate(-x, -y);
+            return None;
+        }
+    };
+
+    let estimate       = analysis.estimate.as_ref();
+
+    // Holding steady still gets a label, but there's no end to project towards
+    let projected      = estimate.filter(|estimate| estimate.remaining.is_finite());
+    let estimated_time = projected.map(|estimate| estimate.remaining);
+
+    if let Some(estimate_y) = layout.estimate {
+        let estimated_text = &match (estimate, analysis.usual.as_ref()) {
+            (Some(estimate), Some(usual)) if estimate.direction == Direction::Discharging => {
+                format!("{} · usually {}", estimate::describe(estimate), estimate::format_duration(usual.remaining))
+            },
+            (Some(estimate), _) => estimate::describe(estimate),
+            (None, _)           => "Collecting data…".to_string(),
+        };
+
+        set_foreground(theme, 0.3, context);
         let extents = context.text_extents(estimated_text);
         let x = width - extents.width - extents.x_bearing;
-        let y = height + 10.0 - extents.y_bearing;
+        let y = estimate_y - extents.height / 2.0 - extents.y_bearing;
 
         context.move_to(x, y);
         context.show_text(estimated_text);
     }
 
-    let mut states = states.iter();
-    let first_state = states.next().unwrap();
-    context.move_to(0.0, height - first_state.get_level() as f64 / 100.0 * height);
+    let last_timestamp = last_state.get_timestamp() as i64;
+    let first_shown    = overlays.iter()
+                                 .filter_map(|overlay| overlay.graph.get_states().first())
+                                 .chain(Some(first_state))
+                                 .map(|state| state.get_timestamp() as i64)
+                                 .min()
+                                 .unwrap();
+    let view_start     = view.start.unwrap_or(first_shown);
+    let view_end       = view.end.unwrap_or(last_timestamp + estimated_time.unwrap_or(0.0) as i64).max(view_start);
+    let time_window    = (view_end - view_start) as f64;
 
-    let time_window = last_state.get_timestamp() as f64 + estimated_time - first_state.get_timestamp() as f64;
+    let x_at = |timestamp: i64| {
+        if time_window > 0.0 {
+            (timestamp - view_start) as f64 / time_window * width
+        } else {
+            0.0
+        }
+    };
 
-    for state in states {
-        let x = (state.get_timestamp() - first_state.get_timestamp()) as f64 / time_window * width;
-        let y = height - state.get_level() as f64 / 100.0 * height;
+    let point = |state: &BatteryState| {
+        let x = x_at(state.get_timestamp() as i64);
+        let y = height - state.get_level().max(0.0).min(100.0) as f64 / 100.0 * height;
 
-        context.line_to(x, y);
+        (x, y)
+    };
+
+    if time_window > 0.0 {
+        draw_time_axis(view_start, time_window, width, height, layout.axis, scale, theme, context);
+    }
+
+    draw_reference_lines(&config.reference_lines, width, height, scale, theme, context);
+
+    // Zoomed in, the line runs on past the edges of the graph
+    context.save();
+    context.rectangle(-4.0, -4.0, width + 8.0, height + 8.0);
+    context.clip();
+
+    if states.len() == 1 {
+        let (r, g, b) = series_color(colors, if first_state.get_charging() { Series::Idle } else { Series::Discharging });
+        let (point_x, point_y) = point(first_state);
+
+        context.set_source_rgba(r, g, b, 1.0);
+        context.arc(point_x, point_y, 3.0, 0.0, 2.0 * ::std::f64::consts::PI);
+        context.fill();
+
+        context.restore();
+        context.translate(-x, -y);
+        return Some((view_start, view_end));
+    }
+
+    let gaps = &analysis.gaps;
+
+    for &gap in gaps.iter() {
+        let (start_x, _) = point(&states[gap - 1]);
+        let (end_x, _)   = point(&states[gap]);
+
+        draw_gap(&states[gap - 1], &states[gap], start_x, end_x, height, theme, context);
     }
 
-    let (r, g, b, a) = (0.7, 0.9, 1.0, 1.0);
-    context.set_source_rgba(r, g, b, a);
-    context.set_line_width(4.0);
     context.set_line_cap(cairo::LineCap::Round);
-    context.stroke_preserve();
 
-    context.line_to(width, height);
+    // Other devices go underneath ours, as plain lines broken where they slept
     context.set_line_width(2.0);
-    context.set_line_cap(cairo::LineCap::Round);
-    context.set_dash(&[0.0, 8.0], 4.0);
-    context.stroke_preserve();
+    for overlay in overlays {
+        let overlay_states = overlay.graph.get_states();
+        let overlay_gaps   = events::find_gaps(overlay_states, config.gap_factor);
+
+        let mut section_start = 0;
+        for &section_end in overlay_gaps.iter().chain(Some(&overlay_states.len())) {
+            let section = &overlay_states[section_start..section_end];
+            section_start = section_end;
+
+            if let Some(first) = section.first() {
+                let (first_x, first_y) = point(first);
+                context.move_to(first_x, first_y);
+
+                for state in &section[1..] {
+                    let (x, y) = point(state);
+                    context.line_to(x, y);
+                }
+            }
+        }
+
+        let (r, g, b) = overlay.color;
+        context.set_source_rgba(r, g, b, 0.8);
+        context.stroke();
+    }
+
+    context.set_line_width(4.0);
+
+    // Draw each stretch between gaps on its own, so sleep doesn't look like a slow drain
+    let mut section_start = 0;
+    for &section_end in gaps.iter().chain(Some(&states.len())) {
+        let section = &states[section_start..section_end];
+        section_start = section_end;
+
+        if section.len() == 1 {
+            let (r, g, b) = series_color(colors, if section[0].get_charging() { Series::Idle } else { Series::Discharging });
+            let (point_x, point_y) = point(&section[0]);
+
+            context.set_source_rgba(r, g, b, 1.0);
+            context.arc(point_x, point_y, 2.0, 0.0, 2.0 * ::std::f64::consts::PI);
+            context.fill();
+            continue;
+        }
+
+        for (series, run) in series_runs(section) {
+            let color = series_color(colors, series);
+
+            let (run_first_x, run_first_y) = point(&run[0]);
+            let (run_last_x, _)            = point(&run[run.len() - 1]);
+
+            context.move_to(run_first_x, run_first_y);
+            for state in &run[1..] {
+                let (x, y) = point(state);
+                context.line_to(x, y);
+            }
+
+            let (r, g, b) = color;
+            context.set_source_rgba(r, g, b, 1.0);
+            context.stroke_preserve();
+
+            context.line_to(run_last_x, height);
+            context.line_to(run_first_x, height);
+            context.close_path();
+            context.set_source(&fill_gradient(color, height, theme));
+            context.fill();
+        }
+    }
+
+    let (last_x, last_y) = point(last_state);
+
+    if let Some(estimate) = projected {
+        let color = series_color(colors, match estimate.direction {
+            Direction::Charging if estimate.remaining > 0.0 => Series::Charging,
+            Direction::Discharging                          => Series::Discharging,
+            _                                               => Series::Idle,
+        });
+
+        // Follow the predicted level, which curves near full and along a calibrated discharge curve
+        context.move_to(last_x, last_y);
+
+        let steps = 20;
+        for step in 1..(steps + 1) {
+            let time = estimate.remaining * step as f64 / steps as f64;
+            let x    = x_at(last_timestamp + time as i64);
+            let y    = height - estimate.level_at(time) / 100.0 * height;
+
+            context.line_to(x, y);
+        }
+
+        let (r, g, b) = color;
+        context.set_source_rgba(r, g, b, 1.0);
+        context.set_line_width(2.0);
+        context.set_dash(&[0.0, 8.0], 4.0);
+        context.stroke_preserve();
+        context.set_dash(&[], 0.0);
+
+        let end_x = x_at(last_timestamp + estimate.remaining as i64);
+
+        context.line_to(end_x, height);
+        context.line_to(last_x, height);
+        context.close_path();
+        context.set_source(&fill_gradient(color, height, theme));
+        context.fill();
+
+        draw_projection_cone(estimate, color, last_x, end_x, height, context);
+    }
+
+    // Everything left of here happened, everything right of it is a guess
+    set_foreground(theme, 0.4, context);
+    context.set_line_width(1.0);
+    context.move_to(snap(last_x, 1.0, scale), 0.0);
+    context.line_to(snap(last_x, 1.0, scale), height);
+    context.stroke();
+
+    context.restore();
+
+    if config.markers {
+        let markers = analysis.events.iter()
+                           .map(|event| (x_at(event.timestamp), event.kind))
+                           .filter(|&(x, _)| x >= 0.0 && x <= width)
+                           .collect::<Vec<_>>();
+
+        draw_markers(&markers, width, height, scale, theme, context);
+    }
+
+    if let Some(legend_y) = layout.legend {
+        draw_legend(theme, overlays, width, legend_y, context);
+    }
+
+    if let (Some(now_y), true) = (layout.now, last_x >= 0.0 && last_x <= width) {
+        context.set_font_size(theme.font.small_size);
+        set_foreground(theme, 0.4, context);
+        let extents = context.text_extents("now");
+        context.move_to(last_x - extents.width / 2.0 - extents.x_bearing, now_y - extents.height / 2.0 - extents.y_bearing);
+        context.show_text("now");
+        context.set_font_size(theme.font.size);
+    }
+
+    if let Some(estimate) = projected {
+        let end_time = last_timestamp + estimate.remaining as i64;
+        let end_x    = x_at(end_time).min(width);
+        let end_y    = height - estimate.level_at(estimate.remaining) / 100.0 * height;
+        let end_text = &format!("{} {}",
+                                if estimate.direction == Direction::Charging { "full at" } else { "empty at" },
+                                Local.timestamp(end_time, 0).format("%H:%M"));
+
+        context.set_font_size(theme.font.small_size);
+        set_foreground(theme, 0.5, context);
+        let extents = context.text_extents(end_text);
+        let text_y  = if end_y > extents.height + 8.0 { end_y - 8.0 } else { end_y + 8.0 + extents.height };
+
+        if end_x > 0.0 {
+            context.move_to(end_x - extents.width - extents.x_bearing, text_y);
+            context.show_text(end_text);
+        }
+        context.set_font_size(theme.font.size);
+    }
+
+    if let Some(hover_x) = hover {
+        let hover_x = hover_x.max(0.0).min(width);
+
+        match estimate {
+            // Past the last sample, show where the projection says we'll be
+            Some(estimate) if hover_x > last_x => {
+                let time    = view_start + (hover_x / width * time_window) as i64;
+                let level   = estimate.level_at((time - last_timestamp) as f64);
+                let level_y = height - level / 100.0 * height;
+
+                draw_tooltip(hover_x, level_y, &[
+                    Local.timestamp(time, 0).format("%a %H:%M").to_string(),
+                    format!("predicted {:.0}%", level),
+                ], width, height, scale, theme, context);
+            },
+            _ => {
+                let index = (0..states.len())
+                    .min_by_key(|&index| (point(&states[index]).0 - hover_x).abs() as i64)
+                    .unwrap();
+
+                let state                = &states[index];
+                let (sample_x, sample_y) = point(state);
+
+                // The first sample has nothing before it to tell charging from sitting at full
+                let series = match (index, state.get_charging()) {
+                    (0, true)  => Series::Idle,
+                    (0, false) => Series::Discharging,
+                    _          => segment_series(&states[index - 1], state),
+                };
+                let status = match series {
+                    Series::Discharging => "on battery",
+                    Series::Charging    => "charging",
+                    Series::Idle        => "plugged in",
+                };
+
+                // Drain around this sample, from its neighbours on either side
+                let before = &states[index.saturating_sub(1)];
+                let after  = &states[(index + 1).min(states.len() - 1)];
+                let time   = after.get_timestamp() as i64 - before.get_timestamp() as i64;
+                let rate   = if time > 0 {
+                    format!("{:+.1}%/h", (after.get_level() - before.get_level()) as f64 / time as f64 * 3600.0)
+                } else {
+                    "".to_string()
+                };
+
+                draw_tooltip(sample_x, sample_y, &[
+                    Local.timestamp(state.get_timestamp() as i64, 0).format("%a %H:%M").to_string(),
+                    format!("{:.1}% · {}", state.get_level(), status),
+                    rate,
+                ], width, height, scale, theme, context);
+            },
+        }
+    }
+
+    context.translate(-x, -y);
+
+    Some((view_start, view_end))
+}
+
+/// Draws a crosshair through a point, with a box of text next to it
+fn draw_tooltip(x: f64, y: f64, lines: &[String], width: f64, height: f64, scale: f64, theme: &Theme, context: &cairo::Context) {
+    set_foreground(theme, 0.5, context);
+    context.set_line_width(1.0);
+    context.move_to(snap(x, 1.0, scale), 0.0);
+    context.line_to(snap(x, 1.0, scale), height);
+    context.stroke();
+
+    context.arc(x, y, 4.0, 0.0, 2.0 * ::std::f64::consts::PI);
+    context.fill();
+
+    context.set_font_size(theme.font.small_size);
+
+    let lines = lines.iter().filter(|line| !line.is_empty()).collect::<Vec<_>>();
+    let line_height = context.font_extents().height;
+    let padding     = line_height / 2.0;
+
+    let box_width  = lines.iter().map(|line| context.text_extents(line).x_advance).fold(0.0, f64::max) + padding * 2.0;
+    let box_height = lines.len() as f64 * line_height + padding * 2.0 - 4.0;
+
+    // Keep the box inside the graph, flipping to the left near the right edge
+    let box_x = if x + 10.0 + box_width > width { x - 10.0 - box_width } else { x + 10.0 };
+    let box_y = (y - box_height / 2.0).max(0.0).min(height - box_height);
+
+    let (r, g, b) = theme.tooltip;
+    context.set_source_rgba(r, g, b, 0.9);
+    context.rectangle(box_x, box_y, box_width, box_height);
+    context.fill();
+
+    set_foreground(theme, 0.8, context);
+    for (index, line) in lines.iter().enumerate() {
+        context.move_to(box_x + padding, box_y + padding + context.font_extents().ascent + index as f64 * line_height);
+        context.show_text(line);
+    }
+
+    context.set_font_size(theme.font.size);
+}
+
+/// Tick spacings to pick from, in seconds
+const TIME_STEPS: [i64; 11] = [60, 60 * 5, 60 * 15, 60 * 30, 3600, 3600 * 2, 3600 * 3, 3600 * 6, 3600 * 12, 86400, 86400 * 7];
+
+/// Picks times for at most `max_ticks` ticks across a time span, on whole hours and days of
+/// the local clock, along with the format to label them with
+pub fn time_ticks(first_timestamp: i64, time_window: f64, max_ticks: f64) -> (Vec<i64>, &'static str) {
+    let step = *TIME_STEPS.iter()
+                          .find(|&&step| time_window / step as f64 <= max_ticks)
+                          .unwrap_or(&TIME_STEPS[TIME_STEPS.len() - 1]);
+
+    let format = if step >= 86400 { "%a %d" } else { "%H:%M" };
+
+    // Line ticks up with whole hours and days on the local clock, not in UTC
+    let offset    = Local.timestamp(first_timestamp, 0).offset().local_minus_utc() as i64;
+    let mut tick  = ((first_timestamp + offset) / step + 1) * step - offset;
+    let last_tick = first_timestamp + time_window as i64;
+
+    let mut ticks = Vec::new();
+    while tick <= last_tick {
+        ticks.push(tick);
+        tick += step;
+    }
+
+    (ticks, format)
+}
+
+/// Draws ticks with local clock times along the bottom, spaced to suit the time span shown
+fn draw_time_axis(first_timestamp: i64, time_window: f64, width: f64, height: f64, labels_y: Option<f64>, scale: f64,
+                  theme: &Theme, context: &cairo::Context) {
+    context.set_font_size(theme.font.small_size);
+
+    // Leave room for the widest label there could be, and as much again around it
+    let max_ticks       = (width / (context.text_extents("Wed 30").x_advance * 2.0)).max(1.0);
+    let (ticks, format) = time_ticks(first_timestamp, time_window, max_ticks);
+
+    context.set_line_width(theme.gridlines.width);
+
+    for &tick in ticks.iter() {
+        let x = snap((tick - first_timestamp) as f64 / time_window * width, theme.gridlines.width, scale);
+
+        set_foreground(theme, theme.gridlines.opacity, context);
+        context.move_to(x, height);
+        context.line_to(x, height + TICK_LENGTH);
+        context.stroke();
+
+        if let Some(labels_y) = labels_y {
+            let text    = &Local.timestamp(tick, 0).format(format).to_string();
+            let extents = context.text_extents(text);
+
+            set_foreground(theme, 0.3, context);
+            context.move_to(x - extents.width / 2.0 - extents.x_bearing, labels_y - extents.height / 2.0 - extents.y_bearing);
+            context.show_text(text);
+        }
+    }
+
+    context.set_font_size(theme.font.size);
+}
+
+/// Shades where the level will probably be, two standard deviations either way
+fn draw_projection_cone(estimate: &Estimate, (r, g, b): Color, from_x: f64, to_x: f64, height: f64, context: &cairo::Context) {
+    let (low, high) = estimate.bounds();
+
+    let steps = 20;
+    let point = |bound: &Estimate, step: i32| {
+        let time = estimate.remaining * step as f64 / steps as f64;
+        let x    = from_x + (to_x - from_x) * step as f64 / steps as f64;
+        let y    = height - bound.level_at(time).max(0.0).min(100.0) / 100.0 * height;
+
+        (x, y)
+    };
+
+    let (start_x, start_y) = point(&high, 0);
+    context.move_to(start_x, start_y);
+
+    for step in 1..(steps + 1) {
+        let (x, y) = point(&high, step);
+        context.line_to(x, y);
+    }
+
+    for step in (0..(steps + 1)).rev() {
+        let (x, y) = point(&low, step);
+        context.line_to(x, y);
+    }
 
-    context.line_to(0.0, height);
     context.close_path();
+    context.set_source_rgba(r, g, b, 0.15);
+    context.fill();
+}
+
+/// Shades the time between two samples the machine was asleep or off for, with the level lost
+fn draw_gap(before: &BatteryState, after: &BatteryState, start_x: f64, end_x: f64, height: f64, theme: &Theme, context: &cairo::Context) {
+    set_foreground(theme, 0.05, context);
+    context.rectangle(start_x, 0.0, end_x - start_x, height);
+    context.fill();
+
+    context.set_font_size(theme.font.small_size);
+
+    let line_height = context.font_extents().height;
+
+    let lost = format!("{:+.0}%", after.get_level() - before.get_level());
+    for &(text, y) in [ ("asleep", line_height),
+                        (lost.as_str(), line_height * 2.0) ].iter() {
+        let extents = context.text_extents(text);
+
+        // Leave the label out rather than spill it over the line around the gap, or out of the graph
+        if extents.width + 4.0 > end_x - start_x || y > height {
+            continue;
+        }
+
+        set_foreground(theme, 0.3, context);
+        context.move_to((start_x + end_x - extents.width) / 2.0 - extents.x_bearing, y);
+        context.show_text(text);
+    }
+
+    context.set_font_size(theme.font.size);
+}
+
+/// Draws dashed lines across the graph at configured levels, labelled at the left end
+fn draw_reference_lines(lines: &[ReferenceLine], width: f64, height: f64, scale: f64, theme: &Theme, context: &cairo::Context) {
+    context.set_font_size(theme.font.small_size);
+    context.set_line_width(theme.gridlines.width);
+    context.set_dash(&[4.0, 4.0], 0.0);
+
+    for line in lines {
+        let y = snap(height - line.level.max(0.0).min(100.0) / 100.0 * height, theme.gridlines.width, scale);
+
+        set_foreground(theme, 0.3, context);
+        context.move_to(0.0, y);
+        context.line_to(width, y);
+        context.stroke();
+
+        let extents = context.text_extents(&line.label);
+
+        // Labels near the top would run into whatever's drawn above the graph
+        if !line.label.is_empty() && y - 4.0 + extents.y_bearing >= 0.0 {
+
+            set_foreground(theme, 0.5, context);
+            context.move_to(4.0 - extents.x_bearing, y - 4.0);
+            context.show_text(&line.label);
+        }
+    }
+
+    context.set_dash(&[], 0.0);
+    context.set_font_size(theme.font.size);
+}
+
+/// Draws a faint line down the graph for each event, with an icon at the top and a label
+/// next to it where there's room before the next one
+fn draw_markers(markers: &[(f64, EventKind)], width: f64, height: f64, scale: f64, theme: &Theme, context: &cairo::Context) {
+    context.set_font_size(theme.font.small_size);
+    context.set_line_width(1.0);
+
+    for (index, &(x, kind)) in markers.iter().enumerate() {
+        let x = snap(x, 1.0, scale);
+
+        set_foreground(theme, 0.15, context);
+        context.move_to(x, 0.0);
+        context.line_to(x, height);
+        context.stroke();
+
+        draw_marker_icon(kind, x, 8.0, theme, context);
+
+        let label   = &marker_label(kind);
+        let extents = context.text_extents(label);
+        let room    = markers.get(index + 1).map_or(width, |&(next_x, _)| next_x) - x;
+
+        if extents.x_advance + 14.0 < room {
+            set_foreground(theme, 0.5, context);
+            context.move_to(x + 8.0, 8.0 - extents.y_bearing - extents.height / 2.0);
+            context.show_text(label);
+        }
+    }
+
+    context.set_font_size(theme.font.size);
+}
+
+fn draw_marker_icon(kind: EventKind, x: f64, y: f64, theme: &Theme, context: &cairo::Context) {
+    let size = 4.0;
+
+    let set_color = |(r, g, b): Color| context.set_source_rgba(r, g, b, 1.0);
+
+    match kind {
+        EventKind::Plug => {
+            set_color(theme.series.charging);
+            context.move_to(x, y - size);
+            context.line_to(x + size, y + size);
+            context.line_to(x - size, y + size);
+            context.close_path();
+            context.fill();
+        },
+        EventKind::Unplug => {
+            set_color(theme.series.discharging);
+            context.move_to(x, y + size);
+            context.line_to(x + size, y - size);
+            context.line_to(x - size, y - size);
+            context.close_path();
+            context.fill();
+        },
+        EventKind::Full => {
+            set_color(theme.series.idle);
+            context.arc(x, y, size, 0.0, 2.0 * ::std::f64::consts::PI);
+            context.fill();
+        },
+        EventKind::Threshold(_) => {
+            set_foreground(theme, 0.8, context);
+            context.move_to(x, y - size);
+            context.line_to(x + size, y);
+            context.line_to(x, y + size);
+            context.line_to(x - size, y);
+            context.close_path();
+            context.fill();
+        },
+        EventKind::Resume => {
+            set_foreground(theme, 0.8, context);
+            context.arc(x, y, size - 0.5, 0.0, 2.0 * ::std::f64::consts::PI);
+            context.stroke();
+        },
+    }
+}
+
+fn marker_label(kind: EventKind) -> String {
+    match kind {
+        EventKind::Plug               => "plugged in".to_string(),
+        EventKind::Unplug             => "unplugged".to_string(),
+        EventKind::Full               => "full".to_string(),
+        EventKind::Threshold(minutes) => format!("{}m left", minutes),
+        EventKind::Resume             => "woke up".to_string(),
+    }
+}
+
+/// What the battery was doing during a stretch of the line
+#[derive(Clone, Copy, PartialEq)]
+pub enum Series {
+    Discharging,
+    Charging,
+
+    /// Plugged in but full, or held at a charge limit
+    Idle,
+}
+
+pub fn series_color(colors: &SeriesColors, series: Series) -> Color {
+    match series {
+        Series::Discharging => colors.discharging,
+        Series::Charging    => colors.charging,
+        Series::Idle        => colors.idle,
+    }
+}
+
+/// Splits a history into runs of the same series. Neighbouring runs share their boundary
+/// sample so the line stays connected.
+fn series_runs(states: &[BatteryState]) -> Vec<(Series, &[BatteryState])> {
+    let mut runs  = Vec::new();
+    let mut start = 0;
+
+    for index in 1..states.len() {
+        let series = segment_series(&states[index - 1], &states[index]);
+        let next   = states.get(index + 1).map(|next| segment_series(&states[index], next));
+
+        if next != Some(series) {
+            runs.push((series, &states[start..(index + 1)]));
+            start = index;
+        }
+    }
+
+    runs
+}
+
+pub fn segment_series(previous: &BatteryState, state: &BatteryState) -> Series {
+    if !state.get_charging() {
+        Series::Discharging
+    } else if state.get_level() >= 99.5 || state.get_level() <= previous.get_level() {
+        Series::Idle
+    } else {
+        Series::Charging
+    }
+}
+
+/// Vertical gradient under the line, fading out towards the bottom
+fn fill_gradient((r, g, b): Color, height: f64, theme: &Theme) -> cairo::LinearGradient {
+    let (top, bottom) = theme.fill_opacity;
 
     let gradient = cairo::LinearGradient::new(0.0, 0.0, 0.0, height);
-    gradient.add_color_stop_rgba(0.0, 0.7, 0.9, 1.0, 0.2);
-    gradient.add_color_stop_rgba(1.0, 0.7, 0.9, 1.0, 0.0);
+    gradient.add_color_stop_rgba(0.0, r, g, b, top);
+    gradient.add_color_stop_rgba(1.0, r, g, b, bottom);
 
-    context.set_source(&gradient);
-    context.fill();
+    gradient
+}
 
-    context.translate(-x, -y);
+/// Explains the line colours, other devices' included, in the top right corner above the graph
+fn draw_legend(theme: &Theme, overlays: &[Overlay], width: f64, y: f64, context: &cairo::Context) {
+    context.set_font_size(theme.font.small_size);
+
+    let series = [ ("plugged in", Series::Idle),
+                   ("charging", Series::Charging),
+                   ("on battery", Series::Discharging) ];
+
+    // Other devices follow ours, leftwards from the right edge
+    let entries = series.iter()
+                        .map(|&(label, series)| (label, series_color(&theme.series, series)))
+                        .chain(overlays.iter().map(|overlay| (overlay.device.as_str(), overlay.color)));
+
+    let size  = context.font_extents().ascent * 0.6;
+    let mut x = width;
+    for (label, (r, g, b)) in entries {
+        let extents = context.text_extents(label);
+        x -= extents.width + extents.x_bearing;
+
+        // Don't run off the left of the graph when it's narrow
+        if x - size * 2.0 < 0.0 {
+            break;
+        }
+
+        set_foreground(theme, 0.5, context);
+        context.move_to(x, y - extents.y_bearing - extents.height / 2.0);
+        context.show_text(label);
+
+        context.set_source_rgba(r, g, b, 1.0);
+        context.rectangle(x - size * 1.75, y - size / 2.0, size, size);
+        context.fill();
+
+        x -= size * 4.0;
+    }
+
+    context.set_font_size(theme.font.size);
+}
+
+fn draw_centered_text(text: &str, width: f64, height: f64, theme: &Theme, context: &cairo::Context) {
+    set_foreground(theme, 0.3, context);
+
+    let extents = context.text_extents(text);
+    let x = (width  - extents.width)  / 2.0 - extents.x_bearing;
+    let y = (height - extents.height) / 2.0 - extents.y_bearing;
+
+    context.move_to(x, y);
+    context.show_text(text);
+}
+
+/// Text, gridlines and the like are all the foreground colour at some opacity
+fn set_foreground(theme: &Theme, alpha: f64, context: &cairo::Context) {
+    let (r, g, b) = theme.foreground;
+    context.set_source_rgba(r, g, b, alpha);
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use estimate::SlopeEstimator;
+    use events::EventConfig;
+    use fixtures::{run, state};
+    use profile::{ProfileEstimator, UsageProfile};
+    use super::*;
+    use super::cairo;
+
+    /// Draws a history on an image the way the window would, pointer over the middle
+    fn draw(states: &[BatteryState]) -> Option<Shown> {
+        let surface   = cairo::ImageSurface::create(cairo::Format::ARgb32, 400, 300);
+        let context   = cairo::Context::new(&surface);
+        let estimator = SlopeEstimator { window: 60 * 5, charge_limit: 100.0 };
+        let config    = Config::default();
+        let usual     = ProfileEstimator { profile: UsageProfile::learn(states) };
+        let analysis  = Analysis::new(states, &estimator, &usual, &EventConfig::from_config(&config));
+
+        draw_graph(&context, 400.0, 300.0, 1.0, states, &analysis, &[], &View::default(), &config.graph, &Theme::dark(), Some(200.0))
+    }
+
+    #[test]
+    fn empty_history() {
+        assert!(draw(&[]).is_none());
+    }
+
+    #[test]
+    fn single_sample() {
+        let shown = draw(&[state(1000, 50.0, false)]).unwrap();
+
+        assert_eq!((shown.start, shown.end), (1000, 1000));
+    }
+
+    #[test]
+    fn all_samples_inside_the_estimate_window() {
+        let shown = draw(&run(1000, 80.0, -0.5, false, 3)).unwrap();
+
+        // Without an estimate the graph ends at the last sample
+        assert_eq!((shown.start, shown.end), (1000, 1120));
+    }
+
+    #[test]
+    fn timestamps_going_backwards() {
+        let mut states = run(10000, 80.0, -0.5, false, 10);
+        states.extend(run(10000 + 600 - 3600, 75.0, -0.5, false, 3));
+
+        let shown = draw(&states).unwrap();
+        assert!(shown.end >= shown.start);
+
+        assert!(draw(&[state(5000, 50.0, false), state(4000, 51.0, false)]).is_some());
+    }
+
+    #[test]
+    fn flat_level_leaves_out_the_projection() {
+        let shown = draw(&run(1000, 60.0, 0.0, false, 30)).unwrap();
+
+        assert_eq!((shown.start, shown.end), (1000, 1000 + 29 * 60));
+    }
+
+    #[test]
+    fn too_small_to_draw_in() {
+        let surface   = cairo::ImageSurface::create(cairo::Format::ARgb32, 10, 10);
+        let context   = cairo::Context::new(&surface);
+        let estimator = SlopeEstimator { window: 60 * 5, charge_limit: 100.0 };
+        let states    = run(1000, 80.0, -0.5, false, 30);
+        let config    = Config::default();
+        let usual     = ProfileEstimator { profile: UsageProfile::learn(&states) };
+        let analysis  = Analysis::new(&states, &estimator, &usual, &EventConfig::from_config(&config));
+
+        assert!(draw_graph(&context, 10.0, 10.0, 1.0, &states, &analysis, &[], &View::default(),
+                           &config.graph, &Theme::dark(), Some(5.0)).is_none());
+    }
 }
@@ -1,22 +1,34 @@
 extern crate gtk;
 extern crate gdk;
 extern crate gdk_sys;
+extern crate priv_battery;
 extern crate protobuf;
 extern crate redis;
+extern crate serde_json;
 
-mod graph;
-mod linegraph;
+use priv_battery::{backtest, calibration, config, estimate, events, graph, hooks, linegraph, mqtt, notify, plot, profile, render, stats, theme};
 
+use calibration::DischargeCurve;
+use config::{Color, Config, NotificationConfig};
+use estimate::{CalibratedEstimator, Direction, Estimate, Estimator, EwmaEstimator, RegressionEstimator, SlopeEstimator};
+use events::EventConfig;
 use graph::{BatteryGraph, BatteryState};
 use gtk::prelude::*;
-use linegraph::create_linegraph;
+use linegraph::{Analysis, LineGraph, Overlay, View};
+use profile::{ProfileEstimator, UsageProfile};
 use protobuf::Message;
 use redis::Commands;
+use stats::Stats;
+use std::cell::{Cell, RefCell};
 use std::env;
 use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
 use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 /// Returns the contents of a file
 fn read_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
@@ -67,46 +79,226 @@ fn get_battery_status() -> (f32, bool) {
     (level, charging)
 }
 
-fn prune_old() {
+/// Drops samples older than the configured number of days, after learning the usage profile
+/// from everything there is for readers that only load what they show
+fn prune_old(config: &Config) {
     let con = get_redis_connection();
-    let buffer: Vec<u8> = con.get("battery:surface").unwrap();
-    let mut graph: BatteryGraph = protobuf::parse_from_bytes(&buffer).unwrap();
+    let mut graph = load_graph(config);
 
-    {
-        let mut states = graph.mut_states();
-        let oldest_timestamp = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as u32 - (60 * 60 * 6); // 6 hours ago
-        let newest_timestamp = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as u32 - (60 * 30);     // 30 minutes ago
+    let profile = UsageProfile::learn(graph.get_states());
+    let _: () = con.set(profile_key(config), serde_json::to_string(&profile).unwrap()).unwrap();
 
-        states.reverse();
+    let now    = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+    let oldest = now - config.history_days as i64 * 86400;
 
-        let mut split_index = 0;
-        for (index, state) in states.iter().enumerate() {
-            let full = state.get_level() >= 100.0;
-            let too_new = state.get_timestamp() > newest_timestamp;
-            let too_old = state.get_timestamp() < oldest_timestamp;
+    let states = graph.take_states().into_iter().filter(|state| state.get_timestamp() as i64 >= oldest).collect();
+    graph.set_states(protobuf::RepeatedField::from_vec(states));
 
-            if (full && !too_new) || too_old {
-                split_index = index;
-                break;
+    // Encodes the same as the single-sample records `log` appends, so the result can still be
+    // searched by `RECORD_SIZE`
+    let buffer = graph.write_to_bytes().unwrap();
+    let _: () = con.set(redis_key(config), buffer).unwrap();
+
+    println!("Kept {} samples from the last {} days", graph.get_states().len(), config.history_days);
+}
+
+fn get_redis_client() -> redis::Client {
+    redis::Client::open("redis://priv-dark-master/").unwrap()
+}
+
+fn get_redis_connection() -> redis::Connection {
+    get_redis_client().get_connection().unwrap()
+}
+
+/// Returns the key this device's history is stored under
+fn redis_key(config: &Config) -> String {
+    format!("battery:{}", config.device)
+}
+
+/// Returns the channel new samples for this device are published on
+fn redis_channel(config: &Config) -> String {
+    format!("battery:{}:samples", config.device)
+}
+
+/// Returns the key this device's fitted discharge curve is stored under
+fn curve_key(config: &Config) -> String {
+    format!("battery:{}:curve", config.device)
+}
+
+/// Returns the key this device's learned usage profile is stored under
+fn profile_key(config: &Config) -> String {
+    format!("battery:{}:profile", config.device)
+}
+
+/// Returns the key holding which low battery thresholds were already notified about
+fn notified_key(config: &Config) -> String {
+    format!("battery:{}:notified", config.device)
+}
+
+/// Returns the key holding which level hooks have fired and not been re-armed yet
+fn hooks_key(config: &Config) -> String {
+    format!("battery:{}:hooks", config.device)
+}
+
+/// Returns the key holding which other devices the window last showed alongside this one
+fn overlays_key(config: &Config) -> String {
+    format!("battery:{}:overlays", config.device)
+}
+
+/// Reads this device's full history from storage
+fn load_graph(config: &Config) -> BatteryGraph {
+    let con = get_redis_connection();
+    let buffer: Option<Vec<u8>> = con.get(redis_key(config)).unwrap();
+
+    // A device that hasn't logged anything yet simply has no history
+    protobuf::parse_from_bytes(&buffer.unwrap_or_default()).unwrap()
+}
+
+/// Size of one stored sample: its framing, timestamp, level and charging flag. That holds as
+/// long as every sample was written by `log` or `prune` with all three set, and timestamps
+/// take five bytes as a varint, which they do from 1978 until 3058.
+const RECORD_SIZE: isize = 15;
+
+/// Reads this device's history from `since` onwards. The stored blob is a run of `RECORD_SIZE`
+/// records in time order, so we binary search it with GETRANGE rather than fetching it all,
+/// falling back to a full read if it doesn't look like that. Estimating needs some history
+/// before `since` as well, which `load_graph_for` adds.
+fn load_graph_since(config: &Config, since: u32) -> BatteryGraph {
+    let con = get_redis_connection();
+    let key = redis_key(config);
+
+    let length: isize = con.strlen(&key).unwrap();
+    let count = length / RECORD_SIZE;
+
+    let timestamp_at = |index: isize| -> Option<u32> {
+        let buffer: Vec<u8> = con.getrange(&key, index * RECORD_SIZE, (index + 1) * RECORD_SIZE - 1).unwrap();
+        let record: BatteryGraph = protobuf::parse_from_bytes(&buffer).ok()?;
+
+        if record.get_states().len() == 1 {
+            Some(record.get_states()[0].get_timestamp())
+        } else {
+            None
+        }
+    };
+
+    let (mut low, mut high) = (0, count);
+    let mut fixed_size = length % RECORD_SIZE == 0;
+
+    while fixed_size && low < high {
+        let middle = (low + high) / 2;
+
+        match timestamp_at(middle) {
+            Some(timestamp) if timestamp < since => low = middle + 1,
+            Some(_)                              => high = middle,
+            None                                 => fixed_size = false,
+        }
+    }
+
+    if !fixed_size {
+        let mut graph = load_graph(config);
+        let states = graph.take_states().into_iter().filter(|state| state.get_timestamp() >= since).collect();
+        graph.set_states(protobuf::RepeatedField::from_vec(states));
+
+        return graph;
+    }
+
+    let buffer: Vec<u8> = con.getrange(&key, low * RECORD_SIZE, -1).unwrap();
+    protobuf::parse_from_bytes(&buffer).unwrap()
+}
+
+/// Reads what it takes to show history from `since` on: that, plus the `lookback` seconds
+/// before it the estimate there depends on. The whole history without a start.
+fn load_graph_for(config: &Config, since: Option<i64>, lookback: u32) -> BatteryGraph {
+    match since {
+        Some(since) => load_graph_since(config, (since - lookback as i64).max(0) as u32),
+        None        => load_graph(config),
+    }
+}
+
+/// Reads just the latest sample, without going through the rest if it's made of whole records
+fn load_last_state(config: &Config) -> Option<BatteryState> {
+    let con = get_redis_connection();
+    let key = redis_key(config);
+
+    let length: isize = con.strlen(&key).unwrap();
+
+    if length > 0 && length % RECORD_SIZE == 0 {
+        let buffer: Vec<u8> = con.getrange(&key, length - RECORD_SIZE, -1).unwrap();
+
+        if let Ok(record) = protobuf::parse_from_bytes::<BatteryGraph>(&buffer) {
+            if record.get_states().len() == 1 {
+                return record.get_states().last().cloned();
             }
         }
+    }
+
+    load_graph(config).get_states().last().cloned()
+}
+
+/// Returns how this device usually gets used as `prune` last learned it, or failing that
+/// whatever can be learned from `states`
+fn load_profile(config: &Config, states: &[BatteryState]) -> UsageProfile {
+    let con = get_redis_connection();
+    let profile: Option<String> = con.get(profile_key(config)).unwrap();
 
-        let new_length = split_index + 1;
-        states.truncate(new_length);
-        states.reverse();
+    match profile {
+        Some(profile) => serde_json::from_str(&profile).expect("Couldn't parse the stored usage profile."),
+        None          => UsageProfile::learn(states),
     }
+}
 
-    let buffer = graph.write_to_bytes().unwrap();
-    let a: () = con.set("battery:surface", buffer).unwrap();
-    println!("{:?}", a);
+/// Returns the configured estimator, following this device's discharge curve if it has one
+fn load_estimator(config: &Config) -> Box<Estimator> {
+    let estimator = estimate::from_config(config);
+
+    let con = get_redis_connection();
+    let curve: Option<String> = con.get(curve_key(config)).unwrap();
+
+    match curve {
+        Some(curve) => Box::new(CalibratedEstimator {
+            inner: estimator,
+            curve: Rc::new(serde_json::from_str(&curve).expect("Couldn't parse the stored discharge curve.")),
+        }),
+        None => estimator,
+    }
 }
 
-fn get_redis_connection() -> redis::Connection {
-    let client = redis::Client::open("redis://priv-dark-master/").unwrap();
-    client.get_connection().unwrap()
+/// Fits a discharge curve on the stored history and saves it for future estimates
+fn calibrate(config: &Config) {
+    let graph = load_graph(config);
+
+    let curve = match DischargeCurve::fit(graph.get_states()) {
+        Some(curve) => curve,
+        None        => return println!("No discharge cycles long enough to calibrate on yet"),
+    };
+
+    let con = get_redis_connection();
+    let _: () = con.set(curve_key(config), serde_json::to_string(&curve).unwrap()).unwrap();
+
+    println!("Fitted a discharge curve on {} cycles", curve.cycles);
+    for &level in [100.0, 80.0, 50.0, 20.0, 10.0, 5.0].iter() {
+        println!("{:>4}% reported = {:>5.1}% of usable energy", level, curve.energy_at(level));
+    }
 }
 
-fn log_state() {
+/// Scores every estimator against how the stored history actually played out
+fn run_backtest(config: &Config) {
+    let graph = load_graph(config);
+
+    let mut estimators: Vec<(String, Box<Estimator>)> = vec![
+        ("slope".to_string(),      Box::new(SlopeEstimator      { window: 60 * 5,         charge_limit: config.charge_limit }) as Box<Estimator>),
+        ("regression".to_string(), Box::new(RegressionEstimator { window: 60 * 20,        charge_limit: config.charge_limit }) as Box<Estimator>),
+        ("ewma".to_string(),       Box::new(EwmaEstimator       { time_constant: 60 * 10, charge_limit: config.charge_limit }) as Box<Estimator>),
+    ];
+
+    // The backtest fits its own discharge curves as it goes, since the stored one was fitted
+    // on what it's about to replay
+    estimators.push(("configured".to_string(), estimate::from_config(config)));
+
+    backtest::run(graph.get_states(), estimators);
+}
+
+fn log_state(config: &Config) {
     let (capacity, charging) = get_battery_status();
 
     let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
@@ -118,19 +310,226 @@ fn log_state() {
     state.set_level(capacity);
     state.set_charging(charging);
 
-    graph.set_states(protobuf::RepeatedField::from_vec(vec![state]));
+    graph.set_states(protobuf::RepeatedField::from_vec(vec![state.clone()]));
 
+    // Concatenated messages parse as one with all their samples, so appending is all it takes.
+    // With every field set, each of these comes out `RECORD_SIZE` bytes long.
     let buffer = graph.write_to_bytes().unwrap();
 
     let con = get_redis_connection();
-    let _: () = con.append("battery:surface", buffer).unwrap();
+    let _: () = con.append(redis_key(config), buffer).unwrap();
+
+    // Let live consumers know without them having to poll the whole history
+    let buffer = state.write_to_bytes().unwrap();
+    let _: () = con.publish(redis_channel(config), buffer).unwrap();
+
+    // Hooks may be all that stands between an empty battery and lost work, so they go first,
+    // and nothing after them that needs the network or a desktop session gets to stop us
+    if config.notifications.is_some() || !config.hooks.is_empty() {
+        let graph    = load_graph(config);
+        let estimate = load_estimator(config).estimate(graph.get_states());
+
+        run_hooks(config, graph.get_states(), estimate.as_ref());
+
+        if let Some(ref notification_config) = config.notifications {
+            let charging = graph.get_states().last().map_or(false, |state| state.get_charging());
+            notify_low_battery(config, notification_config, estimate.as_ref(), charging);
+        }
+    }
+
+    if let Some(ref mqtt_config) = config.mqtt {
+        if let Err(error) = mqtt::publish_state(mqtt_config, &config.device, &state) {
+            eprintln!("Couldn't publish to the MQTT broker: {}", error);
+        }
+    }
+}
+
+/// Runs any configured hooks the latest sample triggered
+fn run_hooks(config: &Config, states: &[BatteryState], estimate: Option<&Estimate>) {
+    let con = get_redis_connection();
+    let fired: Option<String> = con.get(hooks_key(config)).unwrap();
+    let fired: Vec<String> = fired.map_or(Vec::new(), |fired| serde_json::from_str(&fired).unwrap());
+
+    let fired = hooks::run(config, states, estimate, &fired);
+
+    let _: () = con.set(hooks_key(config), serde_json::to_string(&fired).unwrap()).unwrap();
 }
 
-fn show_window() {
+/// Warns on the desktop when the estimated time left crosses one of the configured thresholds
+fn notify_low_battery(config: &Config, notification_config: &NotificationConfig, estimate: Option<&Estimate>, charging: bool) {
     let con = get_redis_connection();
-    let buffer: Vec<u8> = con.get("battery:surface").unwrap();
-    let graph: BatteryGraph = protobuf::parse_from_bytes(&buffer).unwrap();
+    let notified: Option<String> = con.get(notified_key(config)).unwrap();
+    let notified: Vec<u32> = notified.map_or(Vec::new(), |notified| serde_json::from_str(&notified).unwrap());
+
+    let (notified, urgency) = notify::check(notification_config, estimate, charging, &notified);
+
+    if let (Some(urgency), Some(estimate)) = (urgency, estimate) {
+        let body = format!("{} ({:.0}%)", estimate::describe(estimate), estimate.level);
+
+        // Run from cron there may well be no session bus to send it on
+        if let Err(error) = notify::send("Battery low", &body, urgency) {
+            eprintln!("Couldn't send a desktop notification: {}", error);
+        }
+    }
+
+    let _: () = con.set(notified_key(config), serde_json::to_string(&notified).unwrap()).unwrap();
+}
+
+/// Prints the estimated time remaining, for status bars and the like
+fn print_estimate(config: &Config) {
+    let graph = load_graph(config);
+
+    match load_estimator(config).estimate(graph.get_states()) {
+        Some(estimate) => {
+            let direction = match estimate.direction {
+                Direction::Charging    => "charging",
+                Direction::Discharging => "discharging",
+                Direction::Idle        => "idle",
+            };
+
+            println!("{} ({}, {:.1}%/h, confidence {:.0}%)",
+                     estimate::describe(&estimate),
+                     direction,
+                     estimate.rate * 3600.0,
+                     estimate.confidence * 100.0);
+        },
+        None => println!("Not enough data"),
+    }
+
+    let profile = ProfileEstimator { profile: load_profile(config, graph.get_states()) };
+    if let Some(usual) = profile.estimate(graph.get_states()) {
+        println!("{} based on usual usage at this time of the week", estimate::describe(&usual));
+    }
+}
+
+/// Returns a short description of how long ago a timestamp was
+fn format_age(timestamp: u32) -> String {
+    let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+    let age = now - timestamp as i64;
+
+    match age {
+        age if age < 10   => "just now".to_string(),
+        age if age < 60   => format!("{}s ago", age),
+        age if age < 3600 => format!("{}m ago", age / 60),
+        age               => format!("{}h {}m ago", age / 3600, age / 60 % 60),
+    }
+}
+
+/// Longest to wait before trying to resubscribe after losing the connection to Redis
+const MAX_RESUBSCRIBE_DELAY: u64 = 60;
+
+/// Forwards every sample for this device newer than `since`, the last one already loaded,
+/// to the returned receiver. If the connection drops it keeps trying again, waiting longer
+/// each time.
+fn subscribe_samples(config: &Config, since: Option<u32>) -> mpsc::Receiver<BatteryState> {
+    let (sender, receiver) = mpsc::channel();
+    let config = config.clone();
+
+    thread::spawn(move || {
+        let mut delay = 1;
+        let mut last  = since;
+
+        loop {
+            match forward_samples(&config, &sender, &mut last, &mut delay) {
+                // Whoever was listening has gone away
+                Ok(()) => break,
+                Err(error) => {
+                    eprintln!("Lost the connection to Redis ({}), trying again in {}s", error, delay);
+
+                    thread::sleep(Duration::from_secs(delay));
+                    delay = (delay * 2).min(MAX_RESUBSCRIBE_DELAY);
+                },
+            }
+        }
+    });
 
+    receiver
+}
+
+/// Subscribes and forwards samples until the connection fails, or returns once nobody's
+/// receiving any more. `last` is the timestamp of the last sample forwarded.
+fn forward_samples(config: &Config, sender: &mpsc::Sender<BatteryState>, last: &mut Option<u32>, delay: &mut u64) -> redis::RedisResult<()> {
+    let mut pubsub = get_redis_client().get_pubsub()?;
+    pubsub.subscribe(redis_channel(config))?;
+
+    // Anything logged while we weren't subscribed only made it into storage
+    if let Some(since) = *last {
+        let buffer: Option<Vec<u8>> = get_redis_client().get_connection()?.get(redis_key(config))?;
+        let graph: BatteryGraph     = protobuf::parse_from_bytes(&buffer.unwrap_or_default()).unwrap_or_default();
+
+        for state in graph.get_states().iter().filter(|state| state.get_timestamp() > since) {
+            *last = Some(state.get_timestamp());
+
+            if sender.send(state.clone()).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    *delay = 1;
+
+    loop {
+        let message = pubsub.get_message()?;
+        let buffer: Vec<u8> = message.get_payload()?;
+
+        // Not worth giving up the subscription over one bad message
+        let state: BatteryState = match protobuf::parse_from_bytes(&buffer) {
+            Ok(state) => state,
+            Err(_)    => continue,
+        };
+
+        // Published after we subscribed, but already picked up from storage
+        if last.map_or(false, |last| state.get_timestamp() <= last) {
+            continue;
+        }
+
+        *last = Some(state.get_timestamp());
+
+        if sender.send(state).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Finds every other device with a history in storage. SCAN rather than KEYS, so a big
+/// database isn't blocked while it's walked.
+fn list_devices(config: &Config) -> Vec<String> {
+    let con = get_redis_connection();
+    let keys: Vec<String> = con.scan_match("battery:*").unwrap().collect();
+
+    let mut devices = keys.iter()
+                          .map(|key| key["battery:".len()..].to_string())
+                          .filter(|device| !device.contains(':') && *device != config.device)
+                          .collect::<Vec<_>>();
+
+    devices.sort();
+    devices
+}
+
+/// Reads the selected devices' histories over the given span. Colours go by position among
+/// all devices, so a device keeps its colour when others are toggled.
+fn load_overlays(config: &Config, devices: &[String], selected: &[String], colors: &[Color], span: Option<i64>) -> Vec<Overlay> {
+    let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+
+    devices.iter()
+           .enumerate()
+           .filter(|&(_, device)| selected.contains(device))
+           .map(|(index, device)| {
+               let config = Config { device: device.clone(), ..config.clone() };
+
+               Overlay {
+                   device: device.clone(),
+                   color:  colors.iter().cycle().nth(index).cloned().unwrap_or((0.5, 0.5, 0.5)),
+                   graph:  match span {
+                       Some(span) => load_graph_since(&config, (now - span) as u32),
+                       None       => load_graph(&config),
+                   },
+               }
+           })
+           .collect()
+}
+
+fn show_window(config: &Config) {
     if gtk::init().is_err() {
         println!("Failed to initialize GTK.");
         return;
@@ -145,25 +544,15 @@ fn show_window() {
     let monitor_id = screen.get_primary_monitor();
     let monitor = screen.get_monitor_geometry(monitor_id);
 
-    let css_provider = gtk::CssProvider::new();
-    let _ = css_provider.load_from_data("
-    window {
-        background-color: #1d1f21;
-    }
-
-    #header {
-        color: #efefef;
-        padding: 15px 20px;
-        padding-bottom: 10px;
+    let mut theme = theme::load(&config.graph);
+    if theme.follow_gtk {
+        if let Some(style) = window.get_style_context() {
+            theme.follow_gtk_colors(&style);
+        }
     }
 
-    #subheader {
-        color: #abdbfb;
-        padding: 0px 20px;
-        padding-bottom: 15px;
-        /* margin-bottom: 20px; */
-    }
-    ");
+    let css_provider = gtk::CssProvider::new();
+    let _ = css_provider.load_from_data(&theme.css());
     gtk::StyleContext::add_provider_for_screen(&screen, &css_provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
 
     window.set_size_request(500, 400);
@@ -178,15 +567,141 @@ fn show_window() {
     header.set_name("header");
     header.set_xalign(0.0);
 
-    let subheader = gtk::Label::new("dark-surface");
+    let subheader = gtk::Label::new(config.device.as_str());
     subheader.set_name("subheader");
     subheader.set_xalign(0.0);
 
     grid.add(&header);
     grid.add(&subheader);
 
-    let linegraph = create_linegraph(graph);
-    grid.add(&linegraph);
+    let devices  = list_devices(config);
+    let selected: Option<String> = get_redis_connection().get(overlays_key(config)).unwrap();
+    let selected = selected.map_or(Vec::new(), |selected| serde_json::from_str::<Vec<String>>(&selected).unwrap());
+    let colors   = theme.overlays.clone();
+
+    // Open on the last day, the way `render` and `plot` do
+    let span      = Some(86400);
+    let now       = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+    let since     = span.map(|span| now - span);
+    let estimator = load_estimator(config);
+    let lookback  = estimator.lookback();
+    let graph     = load_graph_for(config, since, lookback);
+
+    let stats = gtk::Label::new(Stats::compute(linegraph::states_since(graph.get_states(), since)).report().as_str());
+    stats.set_name("stats");
+    stats.set_xalign(0.0);
+
+    let usual     = ProfileEstimator { profile: load_profile(config, graph.get_states()) };
+    let linegraph = LineGraph::new(estimator, Box::new(usual), config.graph.clone(), EventConfig::from_config(config), theme);
+    linegraph.set_graph(graph);
+    linegraph.set_overlays(load_overlays(config, &devices, &selected, &colors, span));
+    linegraph.set_view(View::last(span, now));
+    grid.add(linegraph.widget());
+
+    // How much history the presets last asked for, so toggled devices load to match
+    let shown_span: Rc<Cell<Option<i64>>> = Rc::new(Cell::new(span));
+    let selected = Rc::new(RefCell::new(selected));
+
+    let toggles = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+    toggles.set_name("overlays");
+    grid.add(&toggles);
+
+    for device in devices.iter() {
+        let toggle = gtk::CheckButton::new_with_label(device);
+        toggle.set_active(selected.borrow().contains(device));
+        toggles.add(&toggle);
+
+        let (config, device, devices, colors) = (config.clone(), device.clone(), devices.clone(), colors.clone());
+        let (selected, shown_span, linegraph) = (selected.clone(), shown_span.clone(), linegraph.clone());
+        toggle.connect_toggled(move |toggle| {
+            let mut selected = selected.borrow_mut();
+
+            selected.retain(|other| *other != device);
+            if toggle.get_active() {
+                selected.push(device.clone());
+            }
+
+            // Remember the selection for next time the window opens
+            let con = get_redis_connection();
+            let _: () = con.set(overlays_key(&config), serde_json::to_string(&*selected).unwrap()).unwrap();
+
+            linegraph.set_overlays(load_overlays(&config, &devices, &selected, &colors, shown_span.get()));
+        });
+    }
+
+    let presets = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+    presets.set_name("presets");
+    grid.add(&presets);
+
+    let stats_panel = gtk::Expander::new("Statistics");
+    stats_panel.add(&stats);
+    grid.add(&stats_panel);
+
+    for &(label, span) in [ ("1h",  Some(3600)),
+                            ("6h",  Some(3600 * 6)),
+                            ("24h", Some(86400)),
+                            ("7d",  Some(86400 * 7)),
+                            ("all", None) ].iter() {
+        let button = gtk::Button::new_with_label(label);
+        presets.add(&button);
+
+        let (config, linegraph, stats) = (config.clone(), linegraph.clone(), stats.clone());
+        let (devices, colors, selected, shown_span) = (devices.clone(), colors.clone(), selected.clone(), shown_span.clone());
+        button.connect_clicked(move |_| {
+            let now   = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+            let since = span.map(|span| now - span);
+
+            // One read for both, the statistics only covering what's shown of it
+            let graph = load_graph_for(&config, since, lookback);
+            stats.set_text(&Stats::compute(linegraph::states_since(graph.get_states(), since)).report());
+
+            linegraph.set_graph(graph);
+            linegraph.set_overlays(load_overlays(&config, &devices, &selected.borrow(), &colors, span));
+            linegraph.set_view(View::last(span, now));
+            shown_span.set(span);
+        });
+    }
+
+    let samples = subscribe_samples(config, linegraph.last_state().map(|state| state.get_timestamp()));
+    let device  = config.device.clone();
+
+    // Every device that could be toggled on, so overlays keep up with whichever are shown.
+    // Anything they already have is skipped when appending, so there's no need to go back.
+    let overlay_samples = devices.iter()
+                                 .map(|device| (device.clone(), subscribe_samples(&Config { device: device.clone(), ..config.clone() }, None)))
+                                 .collect::<Vec<_>>();
+
+    let update_window = move || {
+        let received = samples.try_iter().collect::<Vec<_>>();
+
+        if !received.is_empty() {
+            linegraph.append_states(&received);
+
+            // Keep the statistics to the span the last preset picked
+            let now   = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+            let since = shown_span.get().map(|span| now - span);
+            stats.set_text(&Stats::compute(&linegraph.states_since(since)).report());
+        }
+
+        for &(ref device, ref samples) in overlay_samples.iter() {
+            let received = samples.try_iter().collect::<Vec<_>>();
+
+            if !received.is_empty() {
+                linegraph.append_overlay_states(device, &received);
+            }
+        }
+
+        let subtitle = match linegraph.last_state() {
+            Some(state) => format!("{} · updated {}", device, format_age(state.get_timestamp())),
+            None        => device.clone(),
+        };
+        subheader.set_text(&subtitle);
+
+        gtk::Continue(true)
+    };
+
+    update_window();
+    gtk::timeout_add_seconds(1, update_window);
 
     window.show_all();
 
@@ -198,11 +713,195 @@ fn show_window() {
     gtk::main();
 }
 
+/// Finds the value following a `--name` flag
+fn flag_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+}
+
+/// Parses a range like 90m, 6h or 7d into seconds, or `all` into no limit
+fn parse_span(range: &str) -> Result<Option<i64>, String> {
+    if range == "all" {
+        return Ok(None);
+    }
+
+    let usage = || format!("Expected a range like 6h, 7d or all, not {:?}", range);
+
+    let unit   = range.chars().last().ok_or_else(&usage)?;
+    let number = range[..(range.len() - unit.len_utf8())].parse::<i64>().map_err(|_| usage())?;
+
+    let seconds = match unit {
+        'm' => number.checked_mul(60),
+        'h' => number.checked_mul(3600),
+        'd' => number.checked_mul(86400),
+        _   => None,
+    };
+
+    match seconds {
+        Some(seconds) if seconds > 0 => Ok(Some(seconds)),
+        _                            => Err(usage()),
+    }
+}
+
+/// Parses a size or scale that has to be above zero
+fn parse_positive<T: std::str::FromStr + PartialOrd + Default>(value: &str, what: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(number) if number > T::default() => Ok(number),
+        _                                   => Err(format!("Expected {}, not {:?}", what, value)),
+    }
+}
+
+/// Parses the value of a `--name` flag, or `default` without one. Exits with a usage error
+/// if it doesn't parse.
+fn parsed_flag<T, F>(args: &[String], name: &str, default: &str, parse: F) -> T
+    where F: Fn(&str) -> Result<T, String>
+{
+    match parse(&flag_value(args, name).unwrap_or(default.to_string())) {
+        Ok(value)  => value,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(2);
+        },
+    }
+}
+
+fn render_graph(config: &Config, args: &[String]) {
+    let format = parsed_flag(args, "--format", "png", |format| {
+        render::Format::parse(format).ok_or(format!("Expected a format of png, svg or pdf, not {:?}", format))
+    });
+
+    let width  = parsed_flag(args, "--width",  "800", |width| parse_positive(width, "a width in pixels"));
+    let height = parsed_flag(args, "--height", "400", |height| parse_positive(height, "a height in pixels"));
+    let scale  = parsed_flag(args, "--scale",  "1",   |scale| parse_positive(scale, "a scale factor"));
+    let span   = parsed_flag(args, "--range",  "24h", parse_span);
+    let output = flag_value(args, "--output").unwrap_or(format!("battery.{}", format.extension()));
+
+    // The range counts back from the last sample rather than the clock, so the same history
+    // always renders the same
+    let end       = load_last_state(config).map_or(0, |state| state.get_timestamp() as i64);
+    let estimator = load_estimator(config);
+    let graph     = load_graph_for(config, span.map(|span| end - span), estimator.lookback());
+    let usual     = ProfileEstimator { profile: load_profile(config, graph.get_states()) };
+
+    render::render(&output, format, width, height, scale, graph.get_states(),
+                   &View::last(span, end), &*estimator, &usual, config, &theme::load(&config.graph));
+}
+
+/// Describes the latest sample, for above the terminal chart
+fn status_line(config: &Config, states: &[BatteryState]) -> String {
+    match states.last() {
+        Some(state) => format!("{} · {:.0}% · {} · updated {}",
+                               config.device,
+                               state.get_level(),
+                               if state.get_charging() { "plugged in" } else { "on battery" },
+                               format_age(state.get_timestamp())),
+        None => config.device.clone(),
+    }
+}
+
+fn plot_graph(config: &Config, args: &[String]) {
+    let span      = parsed_flag(args, "--range", "24h", parse_span);
+    let watch     = args.iter().any(|arg| arg == "--watch");
+    let estimator = load_estimator(config);
+    let events    = EventConfig::from_config(config);
+    let theme     = theme::load(&config.graph);
+
+    let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+
+    // Only the range is shown, but the estimate needs what led up to it
+    let mut graph    = load_graph_for(config, span.map(|span| now - span), estimator.lookback());
+    let usual        = ProfileEstimator { profile: load_profile(config, graph.get_states()) };
+    let mut analysis = Analysis::new(graph.get_states(), &*estimator, &usual, &events);
+
+    if !watch {
+        let (columns, rows) = plot::terminal_size();
+        let chart = plot::plot(graph.get_states(), &analysis, &View::last(span, now), &theme, columns, rows.saturating_sub(1));
+
+        return println!("{}\n{}", status_line(config, graph.get_states()), chart);
+    }
+
+    let samples = subscribe_samples(config, graph.get_states().last().map(|state| state.get_timestamp()));
+
+    loop {
+        let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+
+        // Ask every time round, in case the terminal was resized
+        let (columns, rows) = plot::terminal_size();
+        let chart = plot::plot(graph.get_states(), &analysis, &View::last(span, now), &theme, columns, rows.saturating_sub(1));
+
+        // Redraw over the last chart rather than scrolling
+        print!("\x1b[H\x1b[J{}\n{}", status_line(config, graph.get_states()), chart);
+        io::stdout().flush().unwrap();
+
+        // Wake up every second anyway, so the age in the status line keeps up
+        match samples.recv_timeout(Duration::from_secs(1)) {
+            Ok(state) => {
+                graph.mut_states().push(state);
+                analysis = Analysis::new(graph.get_states(), &*estimator, &usual, &events);
+            },
+            Err(mpsc::RecvTimeoutError::Timeout)      => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+fn print_stats(config: &Config, args: &[String]) {
+    let span = parsed_flag(args, "--range", "7d", parse_span);
+    let now  = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+
+    let graph = match span {
+        Some(span) => load_graph_since(config, (now - span) as u32),
+        None       => load_graph(config),
+    };
+
+    println!("{}", Stats::compute(graph.get_states()).report());
+}
+
 fn main() {
+    let config = config::load();
+
     match env::args().skip(1).next().expect("Expected an action").as_ref() {
-        "log"    => return log_state(),
-        "prune"  => return prune_old(),
-        "window" => return show_window(),
-        arg @ _  => panic!("Unrecognized action {:?}", arg)
+        "log"       => return log_state(&config),
+        "prune"     => return prune_old(&config),
+        "estimate"  => return print_estimate(&config),
+        "calibrate" => return calibrate(&config),
+        "backtest"  => return run_backtest(&config),
+        "window"    => return show_window(&config),
+        "render"    => return render_graph(&config, &env::args().skip(2).collect::<Vec<_>>()),
+        "plot"      => return plot_graph(&config, &env::args().skip(2).collect::<Vec<_>>()),
+        "stats"     => return print_stats(&config, &env::args().skip(2).collect::<Vec<_>>()),
+        arg @ _     => panic!("Unrecognized action {:?}", arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_spans() {
+        assert_eq!(parse_span("all"), Ok(None));
+        assert_eq!(parse_span("90m"), Ok(Some(5400)));
+        assert_eq!(parse_span("6h"), Ok(Some(21600)));
+        assert_eq!(parse_span("7d"), Ok(Some(604800)));
+    }
+
+    #[test]
+    fn rejects_malformed_spans() {
+        for range in ["", "h", "6", "6w", "6hh", "6é", "é", "-6h", "0d", "9223372036854775807d"].iter() {
+            assert!(parse_span(range).is_err(), "{:?} should be a usage error", range);
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_positive::<u32>("800", "a width"), Ok(800));
+        assert_eq!(parse_positive::<f64>("1.5", "a scale"), Ok(1.5));
+
+        for size in ["", "0", "-1", "wide", "NaN"].iter() {
+            assert!(parse_positive::<f64>(size, "a size").is_err(), "{:?} should be a usage error", size);
+        }
     }
 }
@@ -0,0 +1,272 @@
+extern crate url;
+
+use config::MqttConfig;
+use graph::BatteryState;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use self::url::Url;
+
+/// Just enough of MQTT 3.1.1 to push a few retained QoS 0 messages
+pub struct MqttClient {
+    stream: TcpStream,
+}
+
+impl MqttClient {
+    pub fn connect(url: &str, client_id: &str, username: Option<&str>, password: Option<&str>) -> io::Result<MqttClient> {
+        let url  = Url::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let host = url.host_str().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "MQTT url has no host"))?;
+        let port = url.port().unwrap_or(1883);
+
+        let mut client = MqttClient { stream: TcpStream::connect((host, port))? };
+
+        let mut flags = 0x02; // clean session
+        if username.is_some() { flags |= 0x80; }
+        if password.is_some() { flags |= 0x40; }
+
+        let mut body = Vec::new();
+        write_string(&mut body, "MQTT");
+        body.push(4);                     // protocol level 3.1.1
+        body.push(flags);
+        body.extend_from_slice(&[0, 60]); // keep alive, in seconds
+        write_string(&mut body, client_id);
+        if let Some(username) = username { write_string(&mut body, username); }
+        if let Some(password) = password { write_string(&mut body, password); }
+
+        client.send(0x10, &body)?;
+
+        let mut connack = [0u8; 4];
+        client.stream.read_exact(&mut connack)?;
+
+        if connack[0] != 0x20 || connack[3] != 0 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                      format!("MQTT broker refused connection (code {})", connack[3])));
+        }
+
+        Ok(client)
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = Vec::new();
+        write_string(&mut body, topic);
+        body.extend_from_slice(payload);
+
+        self.send(if retain { 0x31 } else { 0x30 }, &body)
+    }
+
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(0xe0, &[])
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+        let mut packet = vec![header];
+
+        // Remaining length, seven bits at a time
+        let mut length = body.len();
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 { byte |= 0x80; }
+            packet.push(byte);
+            if length == 0 { break; }
+        }
+
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, string: &str) {
+    let length = string.len() as u16;
+    buffer.push((length >> 8) as u8);
+    buffer.push(length as u8);
+    buffer.extend_from_slice(string.as_bytes());
+}
+
+/// Publishes the latest state, along with Home Assistant discovery messages for it
+pub fn publish_state(config: &MqttConfig, device: &str, state: &BatteryState) -> io::Result<()> {
+    let client_id = format!("{}-{}", config.prefix, device);
+    let mut client = MqttClient::connect(&config.url,
+                                         &client_id,
+                                         config.username.as_ref().map(|s| s.as_ref()),
+                                         config.password.as_ref().map(|s| s.as_ref()))?;
+
+    let state_topic = format!("{}/{}/state", config.prefix, device);
+    let node_id     = format!("{}_{}", config.prefix.replace('/', "_"), device);
+
+    let level_config = json!({
+        "name":                format!("{} battery", device),
+        "unique_id":           format!("{}_level", node_id),
+        "state_topic":         state_topic,
+        "value_template":      "{{ value_json.level }}",
+        "unit_of_measurement": "%",
+        "device_class":        "battery",
+    });
+
+    let charging_config = json!({
+        "name":           format!("{} charging", device),
+        "unique_id":      format!("{}_charging", node_id),
+        "state_topic":    state_topic,
+        "value_template": "{{ 'ON' if value_json.charging else 'OFF' }}",
+        "device_class":   "battery_charging",
+    });
+
+    let payload = json!({
+        "timestamp": state.get_timestamp(),
+        "level":     (state.get_level() * 10.0).round() / 10.0,
+        "charging":  state.get_charging(),
+    });
+
+    client.publish(&format!("{}/sensor/{}/level/config", config.discovery_prefix, node_id),
+                   level_config.to_string().as_bytes(), true)?;
+    client.publish(&format!("{}/binary_sensor/{}/charging/config", config.discovery_prefix, node_id),
+                   charging_config.to_string().as_bytes(), true)?;
+    client.publish(&state_topic, payload.to_string().as_bytes(), true)?;
+
+    client.disconnect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{self, Value};
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    /// A packet as the broker saw it: the first byte, the remaining length bytes and the body
+    type Packet = (u8, Vec<u8>, Vec<u8>);
+
+    fn read_packet(stream: &mut TcpStream) -> Packet {
+        let mut header = [0u8; 1];
+        stream.read_exact(&mut header).unwrap();
+
+        let mut length_bytes = Vec::new();
+        let mut length       = 0;
+        loop {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+
+            length += ((byte[0] & 0x7f) as usize) << (7 * length_bytes.len());
+            length_bytes.push(byte[0]);
+
+            if byte[0] & 0x80 == 0 { break; }
+        }
+
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).unwrap();
+
+        (header[0], length_bytes, body)
+    }
+
+    /// Stands in for a broker on a free local port: accepts one client, acknowledges its
+    /// CONNECT and collects whatever it sends until it disconnects
+    fn broker() -> (String, thread::JoinHandle<Vec<Packet>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url      = format!("mqtt://127.0.0.1:{}", listener.local_addr().unwrap().port());
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let (header, _, body) = read_packet(&mut stream);
+            assert_eq!(header, 0x10);
+            assert_eq!(&body[..7], &[0, 4, b'M', b'Q', b'T', b'T', 4]);
+
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+
+            let mut packets = Vec::new();
+            loop {
+                let packet = read_packet(&mut stream);
+                if packet.0 == 0xe0 {
+                    break;
+                }
+
+                packets.push(packet);
+            }
+
+            packets
+        });
+
+        (url, handle)
+    }
+
+    /// Splits a PUBLISH body into its topic and payload
+    fn split_publish(body: &[u8]) -> (String, Vec<u8>) {
+        let length = (body[0] as usize) << 8 | body[1] as usize;
+
+        (String::from_utf8(body[2..(2 + length)].to_vec()).unwrap(), body[(2 + length)..].to_vec())
+    }
+
+    #[test]
+    fn publishes_retained_state_and_discovery() {
+        let (url, broker) = broker();
+
+        let config = MqttConfig {
+            url:              url,
+            username:         None,
+            password:         None,
+            prefix:           "priv-battery".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+        };
+
+        let mut state = BatteryState::new();
+        state.set_timestamp(1490000000);
+        state.set_level(42.5);
+        state.set_charging(false);
+
+        publish_state(&config, "laptop", &state).unwrap();
+
+        let packets = broker.join().unwrap();
+        assert_eq!(packets.len(), 3);
+
+        let published = packets.iter()
+                               .map(|&(header, _, ref body)| {
+                                   assert_eq!(header, 0x31, "should be a retained QoS 0 PUBLISH");
+
+                                   let (topic, payload) = split_publish(body);
+                                   (topic, serde_json::from_slice::<Value>(&payload).unwrap())
+                               })
+                               .collect::<Vec<_>>();
+
+        assert_eq!(published[0].0, "homeassistant/sensor/priv-battery_laptop/level/config");
+        assert_eq!(published[0].1["state_topic"], "priv-battery/laptop/state");
+        assert_eq!(published[0].1["unique_id"], "priv-battery_laptop_level");
+        assert_eq!(published[0].1["device_class"], "battery");
+
+        assert_eq!(published[1].0, "homeassistant/binary_sensor/priv-battery_laptop/charging/config");
+        assert_eq!(published[1].1["state_topic"], "priv-battery/laptop/state");
+        assert_eq!(published[1].1["unique_id"], "priv-battery_laptop_charging");
+        assert_eq!(published[1].1["device_class"], "battery_charging");
+
+        assert_eq!(published[2].0, "priv-battery/laptop/state");
+        assert_eq!(published[2].1, json!({ "timestamp": 1490000000, "level": 42.5, "charging": false }));
+    }
+
+    #[test]
+    fn encodes_long_remaining_lengths() {
+        let (url, broker) = broker();
+
+        // Bodies are the two length bytes, the one byte topic and the payload
+        let lengths = [127, 128, 200, 16383, 16384, 100000];
+
+        let mut client = MqttClient::connect(&url, "test", None, None).unwrap();
+        for &length in lengths.iter() {
+            client.publish("t", &vec![b'x'; length - 3], false).unwrap();
+        }
+        client.disconnect().unwrap();
+
+        let packets = broker.join().unwrap();
+        let encoded = packets.iter().map(|&(_, ref length_bytes, _)| length_bytes.clone()).collect::<Vec<_>>();
+
+        assert_eq!(encoded, vec![vec![0x7f],
+                                 vec![0x80, 0x01],
+                                 vec![0xc8, 0x01],
+                                 vec![0xff, 0x7f],
+                                 vec![0x80, 0x80, 0x01],
+                                 vec![0xa0, 0x8d, 0x06]]);
+
+        for (&(header, _, ref body), &length) in packets.iter().zip(lengths.iter()) {
+            assert_eq!(header, 0x30);
+            assert_eq!(body.len(), length);
+            assert_eq!(split_publish(body), ("t".to_string(), vec![b'x'; length - 3]));
+        }
+    }
+}
@@ -0,0 +1,233 @@
+extern crate dbus;
+
+use config::NotificationConfig;
+use estimate::{Direction, Estimate};
+use self::dbus::arg::Variant;
+use self::dbus::{BusType, Connection, Message};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Urgency {
+    Low      = 0,
+    Normal   = 1,
+    Critical = 2,
+}
+
+/// Sends a desktop notification through the freedesktop Notifications interface
+pub fn send(summary: &str, body: &str, urgency: Urgency) -> Result<(), dbus::Error> {
+    let connection = Connection::get_private(BusType::Session)?;
+
+    let mut hints = HashMap::new();
+    hints.insert("urgency", Variant(urgency as u8));
+
+    let message = Message::new_method_call("org.freedesktop.Notifications",
+                                           "/org/freedesktop/Notifications",
+                                           "org.freedesktop.Notifications",
+                                           "Notify")?
+        .append3("priv-battery", 0u32, "battery-caution")
+        .append3(summary, body, Vec::<String>::new())
+        .append2(hints, -1i32);
+
+    connection.send_with_reply_and_block(message, 2000)?;
+
+    Ok(())
+}
+
+/// Works out which thresholds a new estimate crossed, given the ones already notified about
+/// during this discharge. `charging` is whether the latest sample was plugged in. Returns the
+/// thresholds to remember and the urgency to notify with, if at all.
+pub fn check(config: &NotificationConfig, estimate: Option<&Estimate>, charging: bool, notified: &[u32]) -> (Vec<u32>, Option<Urgency>) {
+    // Plugging in starts a fresh discharge next time, and there's nothing to warn about meanwhile
+    if charging {
+        return (Vec::new(), None);
+    }
+
+    let estimate = match estimate {
+        Some(estimate) if estimate.direction == Direction::Discharging => estimate,
+        _ => return (notified.to_vec(), None),
+    };
+
+    let minutes_left = estimate.remaining / 60.0;
+
+    let crossed = config.thresholds.iter()
+                                   .cloned()
+                                   .filter(|&threshold| minutes_left <= threshold as f64)
+                                   .filter(|threshold| !notified.contains(threshold))
+                                   .collect::<Vec<_>>();
+
+    let mut remembered = notified.to_vec();
+    remembered.extend(crossed.iter().cloned());
+
+    // Only the most pressing threshold is worth a notification when several are crossed at once
+    let urgency = crossed.iter().min().map(|&threshold| urgency_for(config, threshold));
+
+    (remembered, urgency)
+}
+
+/// The last threshold is critical, anything more than an hour out is only informational
+fn urgency_for(config: &NotificationConfig, threshold: u32) -> Urgency {
+    match config.thresholds.iter().min() {
+        Some(&lowest) if threshold <= lowest => Urgency::Critical,
+        _ if threshold > 60                  => Urgency::Low,
+        _                                    => Urgency::Normal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc;
+    use std::thread;
+    use super::*;
+    use super::dbus::ConnectionItem;
+
+    /// Draining one percent a minute, so the level is the minutes left
+    fn discharging(minutes: f64) -> Estimate {
+        Estimate::new(minutes, -1.0 / 60.0, 0.0, 1.0, false, 100.0)
+    }
+
+    fn thresholds(thresholds: &[u32]) -> NotificationConfig {
+        NotificationConfig { thresholds: thresholds.to_vec() }
+    }
+
+    #[test]
+    fn each_threshold_once_per_discharge() {
+        let config = thresholds(&[30, 15, 5]);
+
+        assert_eq!(check(&config, Some(&discharging(40.0)), false, &[]), (vec![], None));
+        assert_eq!(check(&config, Some(&discharging(29.0)), false, &[]), (vec![30], Some(Urgency::Normal)));
+        assert_eq!(check(&config, Some(&discharging(28.0)), false, &[30]), (vec![30], None));
+
+        // The estimate can wander back up without re-arming anything
+        assert_eq!(check(&config, Some(&discharging(35.0)), false, &[30]), (vec![30], None));
+
+        assert_eq!(check(&config, Some(&discharging(14.0)), false, &[30]), (vec![30, 15], Some(Urgency::Normal)));
+    }
+
+    #[test]
+    fn several_thresholds_at_once() {
+        let (remembered, urgency) = check(&thresholds(&[30, 15, 5]), Some(&discharging(4.0)), false, &[]);
+
+        assert_eq!(remembered, vec![30, 15, 5]);
+        assert_eq!(urgency, Some(Urgency::Critical));
+    }
+
+    #[test]
+    fn charging_starts_over() {
+        let config   = thresholds(&[30, 15, 5]);
+        let charging = Estimate::new(50.0, 0.01, 0.0, 1.0, true, 100.0);
+        let idle     = Estimate::new(50.0, 0.0, 0.0, 1.0, false, 100.0);
+
+        assert_eq!(check(&config, Some(&charging), true, &[30, 15]), (vec![], None));
+
+        // Plugged in but not gaining, say at the charge limit, still starts over
+        assert_eq!(check(&config, Some(&idle), true, &[30, 15]), (vec![], None));
+        assert_eq!(check(&config, None, true, &[30, 15]), (vec![], None));
+
+        // A stale estimate that still says discharging doesn't warn while plugged in
+        assert_eq!(check(&config, Some(&discharging(4.0)), true, &[30]), (vec![], None));
+
+        // On battery, not knowing or not moving says nothing about the charger
+        assert_eq!(check(&config, Some(&idle), false, &[30, 15]), (vec![30, 15], None));
+        assert_eq!(check(&config, None, false, &[30, 15]), (vec![30, 15], None));
+
+        assert_eq!(check(&config, Some(&discharging(29.0)), false, &[]), (vec![30], Some(Urgency::Normal)));
+    }
+
+    #[test]
+    fn urgency_rises_as_time_runs_out() {
+        let config = thresholds(&[120, 30, 5]);
+
+        let urgencies = [119.0, 29.0, 4.0].iter()
+                                          .scan(Vec::new(), |notified, &minutes| {
+                                              let (remembered, urgency) = check(&config, Some(&discharging(minutes)), false, notified);
+                                              *notified = remembered;
+
+                                              Some(urgency.unwrap())
+                                          })
+                                          .collect::<Vec<_>>();
+
+        assert_eq!(urgencies, vec![Urgency::Low, Urgency::Normal, Urgency::Critical]);
+        assert!((Urgency::Low as u8) < (Urgency::Normal as u8) && (Urgency::Normal as u8) < (Urgency::Critical as u8));
+    }
+
+    /// A session bus of our own, shut down again when dropped
+    struct SessionBus(Child);
+
+    impl Drop for SessionBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// What a notification server got asked to show
+    type Notification = (String, u32, String, String, String, Vec<String>, u8, i32);
+
+    #[test]
+    fn sends_over_the_session_bus() {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Couldn't start dbus-daemon, which this test needs.");
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let _bus = SessionBus(daemon);
+
+        // Everything in here talks to the session bus, and this one is ours
+        env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+
+        let (ready_sender, ready) = mpsc::channel();
+
+        // Stands in for the notification daemon, answering the first call and handing over what it was
+        let server = thread::spawn(move || -> Option<Notification> {
+            let connection = Connection::get_private(BusType::Session).unwrap();
+            connection.register_name("org.freedesktop.Notifications", 0).unwrap();
+            connection.register_object_path("/org/freedesktop/Notifications").unwrap();
+            ready_sender.send(()).unwrap();
+
+            let mut timeouts = 0;
+            for item in connection.iter(1000) {
+                let call = match item {
+                    ConnectionItem::MethodCall(call) => call,
+                    ConnectionItem::Nothing if timeouts < 5 => { timeouts += 1; continue },
+                    ConnectionItem::Nothing => return None,
+                    _ => continue,
+                };
+
+                let notification = {
+                    let mut args = call.iter_init();
+
+                    let app_name    = args.read::<&str>().unwrap().to_string();
+                    let replaces_id = args.read::<u32>().unwrap();
+                    let icon        = args.read::<&str>().unwrap().to_string();
+                    let summary     = args.read::<&str>().unwrap().to_string();
+                    let body        = args.read::<&str>().unwrap().to_string();
+                    let actions     = args.read::<Vec<&str>>().unwrap().iter().map(|action| action.to_string()).collect();
+                    let hints       = args.read::<HashMap<&str, Variant<u8>>>().unwrap();
+                    let timeout     = args.read::<i32>().unwrap();
+
+                    (app_name, replaces_id, icon, summary, body, actions, hints.get("urgency").map_or(255, |urgency| urgency.0), timeout)
+                };
+
+                connection.send(call.method_return().append1(1u32)).unwrap();
+
+                return Some(notification);
+            }
+
+            None
+        });
+
+        ready.recv().unwrap();
+        send("Battery low", "0h 10m left (8%)", Urgency::Critical).unwrap();
+
+        assert_eq!(server.join().unwrap(),
+                   Some(("priv-battery".to_string(), 0, "battery-caution".to_string(),
+                         "Battery low".to_string(), "0h 10m left (8%)".to_string(),
+                         Vec::new(), Urgency::Critical as u8, -1)));
+    }
+}
@@ -0,0 +1,241 @@
+extern crate chrono;
+
+use config::Color;
+use estimate::{self, Direction};
+use graph::BatteryState;
+use linegraph::{self, Analysis, Series, View};
+use self::chrono::{Local, TimeZone};
+use std::process::{Command, Stdio};
+use theme::Theme;
+
+/// Columns taken up by the level labels and the axis left of the chart
+const LABEL_WIDTH: usize = 6;
+
+/// Braille characters are two dots wide and four high
+const DOTS_X: usize = 2;
+const DOTS_Y: usize = 4;
+
+/// Which bit of a braille character each dot is, by column and then row
+const DOT_BITS: [[u32; DOTS_Y]; DOTS_X] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+/// A grid of braille characters to draw dots into
+struct Canvas {
+    columns: usize,
+    rows:    usize,
+    dots:    Vec<u32>,
+
+    /// Colour of whatever was drawn into each character last
+    colors: Vec<Option<Color>>,
+}
+
+impl Canvas {
+    fn new(columns: usize, rows: usize) -> Canvas {
+        Canvas {
+            columns: columns,
+            rows:    rows,
+            dots:    vec![0; columns * rows],
+            colors:  vec![None; columns * rows],
+        }
+    }
+
+    /// Sets a dot, leaving out any that fall outside the canvas
+    fn set(&mut self, (x, y): (i64, i64), color: Color) {
+        if x < 0 || y < 0 || x >= (self.columns * DOTS_X) as i64 || y >= (self.rows * DOTS_Y) as i64 {
+            return;
+        }
+
+        let (x, y) = (x as usize, y as usize);
+        let cell   = y / DOTS_Y * self.columns + x / DOTS_X;
+
+        self.dots[cell]  |= DOT_BITS[x % DOTS_X][y % DOTS_Y];
+        self.colors[cell] = Some(color);
+    }
+
+    /// Draws a straight line, or every other dot of one when `dotted`
+    fn line(&mut self, from: (i64, i64), to: (i64, i64), color: Color, dotted: bool) {
+        let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).max(1);
+
+        for step in 0..(steps + 1) {
+            if dotted && step % 2 == 1 {
+                continue;
+            }
+
+            let x = from.0 + (to.0 - from.0) * step / steps;
+            let y = from.1 + (to.1 - from.1) * step / steps;
+
+            self.set((x, y), color);
+        }
+    }
+
+    fn row(&self, row: usize) -> String {
+        (0..self.columns).map(|column| {
+            let cell = row * self.columns + column;
+
+            match (self.dots[cell], self.colors[cell]) {
+                (0, _)              => " ".to_string(),
+                (dots, Some(color)) => paint(color, ::std::char::from_u32(0x2800 + dots).unwrap()),
+                (dots, None)        => ::std::char::from_u32(0x2800 + dots).unwrap().to_string(),
+            }
+        }).collect()
+    }
+}
+
+/// Wraps a character in a 24-bit colour escape
+fn paint((r, g, b): Color, character: char) -> String {
+    format!("\x1b[38;2;{};{};{}m{}\x1b[0m", (r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8, character)
+}
+
+/// Asks the terminal how many columns and rows it has, assuming 80 by 24 if it won't say
+pub fn terminal_size() -> (usize, usize) {
+    let output = Command::new("stty").arg("size").stdin(Stdio::inherit()).output();
+
+    let size = output.ok()
+                     .and_then(|output| String::from_utf8(output.stdout).ok())
+                     .and_then(|size| {
+                         let mut parts = size.split_whitespace().map(|part| part.parse::<usize>().ok());
+
+                         match (parts.next(), parts.next()) {
+                             (Some(Some(rows)), Some(Some(columns))) => Some((columns, rows)),
+                             _                                       => None,
+                         }
+                     });
+
+    size.unwrap_or((80, 24))
+}
+
+/// Draws the history and projection as a braille chart with level labels, a time axis and
+/// the estimate underneath, filling the given number of columns and rows. `analysis` has to
+/// be of `states`.
+pub fn plot(states: &[BatteryState], analysis: &Analysis, view: &View, theme: &Theme, columns: usize, rows: usize) -> String {
+    let (first_state, last_state) = match (states.first(), states.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _                         => return "Collecting data…\n".to_string(),
+    };
+
+    // Leave room for the axis, its labels and the estimate
+    let chart_columns = columns.saturating_sub(LABEL_WIDTH + 1).max(1);
+    let chart_rows    = rows.saturating_sub(3).max(2);
+
+    let estimate       = analysis.estimate.as_ref();
+    let projected      = estimate.filter(|estimate| estimate.remaining.is_finite());
+    let last_timestamp = last_state.get_timestamp() as i64;
+    let view_start     = view.start.unwrap_or(first_state.get_timestamp() as i64);
+    let view_end       = view.end.unwrap_or(last_timestamp + projected.map_or(0.0, |estimate| estimate.remaining) as i64).max(view_start);
+    let time_window    = (view_end - view_start) as f64;
+
+    let dots_wide = (chart_columns * DOTS_X) as f64;
+    let dots_high = (chart_rows * DOTS_Y) as f64;
+
+    let column_at = |timestamp: i64| {
+        if time_window > 0.0 {
+            ((timestamp - view_start) as f64 / time_window * (dots_wide - 1.0)).round() as i64
+        } else {
+            0
+        }
+    };
+
+    let dot_at = |timestamp: i64, level: f64| {
+        (column_at(timestamp), ((100.0 - level.max(0.0).min(100.0)) / 100.0 * (dots_high - 1.0)).round() as i64)
+    };
+
+    let state_dot = |state: &BatteryState| dot_at(state.get_timestamp() as i64, state.get_level() as f64);
+
+    let mut canvas = Canvas::new(chart_columns, chart_rows);
+
+    if states.len() == 1 {
+        let series = if first_state.get_charging() { Series::Idle } else { Series::Discharging };
+        canvas.set(state_dot(first_state), linegraph::series_color(&theme.series, series));
+    }
+
+    // Leave gaps where the machine slept, like the window does
+    for index in 1..states.len() {
+        if analysis.gaps.contains(&index) {
+            continue;
+        }
+
+        let color = linegraph::series_color(&theme.series, linegraph::segment_series(&states[index - 1], &states[index]));
+        canvas.line(state_dot(&states[index - 1]), state_dot(&states[index]), color, false);
+    }
+
+    if let Some(estimate) = projected {
+        let series = match estimate.direction {
+            Direction::Charging if estimate.remaining > 0.0 => Series::Charging,
+            Direction::Discharging                          => Series::Discharging,
+            _                                               => Series::Idle,
+        };
+        let color = linegraph::series_color(&theme.series, series);
+
+        let steps    = 20;
+        let mut from = state_dot(last_state);
+
+        for step in 1..(steps + 1) {
+            let time = estimate.remaining * step as f64 / steps as f64;
+            let to   = dot_at(last_timestamp + time as i64, estimate.level_at(time));
+
+            canvas.line(from, to, color, true);
+            from = to;
+        }
+    }
+
+    let mut output = String::new();
+
+    let label_row = |level: f64| dot_at(view_start, level).1 as usize / DOTS_Y;
+
+    for row in 0..chart_rows {
+        let label = [100.0, 50.0, 0.0].iter().find(|&&level| label_row(level) == row);
+
+        match label {
+            Some(level) => output.push_str(&format!("{:>4}% ┤", level)),
+            None        => output.push_str(&format!("{:>6}│", "")),
+        }
+
+        output.push_str(&canvas.row(row));
+        output.push('\n');
+    }
+
+    // Ticks need room for their labels, with a space either side
+    let (ticks, format) = linegraph::time_ticks(view_start, time_window, (chart_columns / 8) as f64);
+
+    let mut axis   = vec!['─'; chart_columns];
+    let mut labels = vec![' '; chart_columns];
+    let mut free   = 0;
+
+    for &tick in ticks.iter() {
+        let column = column_at(tick) as usize / DOTS_X;
+        if column >= chart_columns {
+            continue;
+        }
+
+        axis[column] = '┬';
+
+        let text  = Local.timestamp(tick, 0).format(format).to_string().chars().collect::<Vec<_>>();
+        let start = column.saturating_sub(text.len() / 2);
+
+        if start >= free && start + text.len() <= chart_columns {
+            labels[start..(start + text.len())].copy_from_slice(&text);
+            free = start + text.len() + 1;
+        }
+    }
+
+    output.push_str(&format!("{:>6}└{}\n", "", axis.into_iter().collect::<String>()));
+    output.push_str(&format!("{:>7}{}\n", "", labels.into_iter().collect::<String>()));
+
+    let estimated_text = match (estimate, analysis.usual.as_ref()) {
+        (Some(estimate), Some(usual)) if estimate.direction == Direction::Discharging => {
+            format!("{} · usually {}", estimate::describe(estimate), estimate::format_duration(usual.remaining))
+        },
+        (Some(estimate), _) => estimate::describe(estimate),
+        (None, _)           => "Collecting data…".to_string(),
+    };
+
+    let end_text = match projected {
+        Some(estimate) if estimate.remaining > 0.0 => format!(" · {} {}",
+            if estimate.direction == Direction::Charging { "full at" } else { "empty at" },
+            Local.timestamp(last_timestamp + estimate.remaining as i64, 0).format("%H:%M")),
+        _ => String::new(),
+    };
+
+    output.push_str(&format!("{:>7}{}{}", "", estimated_text, end_text));
+
+    output
+}
@@ -0,0 +1,118 @@
+extern crate chrono;
+
+use estimate::{Direction, Estimate, Estimator};
+use graph::BatteryState;
+use self::chrono::{Datelike, Local, TimeZone, Timelike};
+
+const HOURS_PER_WEEK: usize = 24 * 7;
+
+/// Gaps longer than this are the machine sleeping, not using power
+pub const MAX_SAMPLE_GAP: i64 = 60 * 30;
+
+/// How far ahead to walk before giving up on the battery ever running out
+const MAX_LOOKAHEAD: f64 = 60.0 * 60.0 * 24.0 * 7.0;
+
+/// Average discharge rate for every hour of the week, learned from history
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UsageProfile {
+    /// Drain in percent per second, indexed by hour since Monday midnight
+    rates: Vec<Option<f64>>,
+
+    /// Drain over all of history, for hours we haven't seen yet
+    overall: Option<f64>,
+}
+
+impl UsageProfile {
+    pub fn learn(states: &[BatteryState]) -> UsageProfile {
+        let mut drops = vec![0.0; HOURS_PER_WEEK];
+        let mut times = vec![0.0; HOURS_PER_WEEK];
+
+        for pair in states.windows(2) {
+            let time = pair[1].get_timestamp() as i64 - pair[0].get_timestamp() as i64;
+
+            if pair[0].get_charging() || pair[1].get_charging() || time <= 0 || time > MAX_SAMPLE_GAP {
+                continue;
+            }
+
+            let hour = hour_of_week(pair[0].get_timestamp() as i64);
+            drops[hour] += (pair[0].get_level() - pair[1].get_level()) as f64;
+            times[hour] += time as f64;
+        }
+
+        let total_time = times.iter().sum::<f64>();
+        let overall    = if total_time > 0.0 { Some(drops.iter().sum::<f64>() / total_time) } else { None };
+
+        let rates = drops.iter()
+                         .zip(times.iter())
+                         .map(|(&drop, &time)| if time > 0.0 { Some(drop / time) } else { None })
+                         .collect();
+
+        UsageProfile {
+            rates:   rates,
+            overall: overall,
+        }
+    }
+
+    /// Returns the expected drain at a given time, in percent per second
+    pub fn rate_at(&self, timestamp: i64) -> Option<f64> {
+        self.rates[hour_of_week(timestamp)].or(self.overall)
+    }
+
+    /// Seconds until empty, walking forward through the expected rate for each hour
+    pub fn time_to_empty(&self, timestamp: i64, level: f64) -> Option<f64> {
+        let mut time      = timestamp;
+        let mut level     = level;
+        let mut remaining = 0.0;
+
+        while remaining < MAX_LOOKAHEAD {
+            let rate = self.rate_at(time)?;
+            let step = (3600 - time % 3600) as f64;
+
+            if rate > 0.0 && rate * step >= level {
+                return Some(remaining + level / rate);
+            }
+
+            level     -= rate.max(0.0) * step;
+            remaining += step;
+            time      += step as i64;
+        }
+
+        None
+    }
+}
+
+/// Predicts remaining time from how this machine is usually used at this time of the week
+pub struct ProfileEstimator {
+    pub profile: UsageProfile,
+}
+
+impl Estimator for ProfileEstimator {
+    fn estimate(&self, states: &[BatteryState]) -> Option<Estimate> {
+        let last_state = states.last()?;
+        if last_state.get_charging() {
+            return None;
+        }
+
+        let timestamp = last_state.get_timestamp() as i64;
+        let level     = last_state.get_level() as f64;
+        let rate      = self.profile.rate_at(timestamp)?;
+        let remaining = self.profile.time_to_empty(timestamp, level)?;
+
+        let mut estimate = Estimate::new(level, -rate, 0.0, 0.5, false, 100.0);
+        estimate.direction = Direction::Discharging;
+        estimate.remaining = remaining;
+
+        Some(estimate)
+    }
+
+    fn lookback(&self) -> u32 {
+        // Everything it knows was learned beforehand
+        0
+    }
+}
+
+fn hour_of_week(timestamp: i64) -> usize {
+    let time = Local.timestamp(timestamp, 0);
+
+    time.weekday().num_days_from_monday() as usize * 24 + time.hour() as usize
+}
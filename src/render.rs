@@ -0,0 +1,77 @@
+extern crate cairo;
+
+use config::Config;
+use estimate::Estimator;
+use events::EventConfig;
+use graph::BatteryState;
+use linegraph::{Analysis, View, draw_graph};
+use std::fs::File;
+use std::path::Path;
+use theme::Theme;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "png" => Some(Format::Png),
+            "svg" => Some(Format::Svg),
+            "pdf" => Some(Format::Pdf),
+            _     => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Png => "png",
+            Format::Svg => "svg",
+            Format::Pdf => "pdf",
+        }
+    }
+}
+
+/// Draws the graph to a file without a display, the same way the window would. `scale` only
+/// affects PNGs, which come out that many times the size with the same layout.
+pub fn render<P: AsRef<Path>>(path: P, format: Format, width: u32, height: u32, scale: f64, states: &[BatteryState],
+                              view: &View, estimator: &Estimator, usual: &Estimator, config: &Config, theme: &Theme) {
+    let (width, height) = (width as f64, height as f64);
+
+    match format {
+        Format::Png => {
+            let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, (width * scale) as i32, (height * scale) as i32);
+            let context = cairo::Context::new(&surface);
+            context.scale(scale, scale);
+            draw(&context, width, height, scale, states, view, estimator, usual, config, theme);
+
+            let mut file = File::create(path).expect("Couldn't create the output file.");
+            surface.write_to_png(&mut file).expect("Couldn't write the PNG.");
+        },
+        Format::Svg => {
+            let surface = cairo::SvgSurface::new(width, height, path);
+            draw(&cairo::Context::new(&surface), width, height, 1.0, states, view, estimator, usual, config, theme);
+            surface.finish();
+        },
+        Format::Pdf => {
+            let surface = cairo::PdfSurface::new(width, height, path);
+            draw(&cairo::Context::new(&surface), width, height, 1.0, states, view, estimator, usual, config, theme);
+            surface.finish();
+        },
+    }
+}
+
+fn draw(context: &cairo::Context, width: f64, height: f64, scale: f64, states: &[BatteryState],
+        view: &View, estimator: &Estimator, usual: &Estimator, config: &Config, theme: &Theme) {
+    // There's no window behind us to provide the background
+    let (r, g, b) = theme.background;
+    context.set_source_rgb(r, g, b);
+    context.paint();
+
+    let analysis = Analysis::new(states, estimator, usual, &EventConfig::from_config(config));
+    draw_graph(context, width, height, scale, states, &analysis, &[], view, &config.graph, theme, None);
+}
+
@@ -0,0 +1,260 @@
+use estimate::format_duration;
+use graph::BatteryState;
+use profile::MAX_SAMPLE_GAP;
+
+/// A discharge going below this counts as a deep one
+const DEEP_DISCHARGE_LEVEL: f32 = 20.0;
+
+/// Drain is only counted as peak drain when it lasts at least this many seconds
+const PEAK_WINDOW: i64 = 60 * 10;
+
+/// Width of the level bands in the histogram
+const BAND_WIDTH: usize = 10;
+
+/// Longest bar in the histogram, in characters
+const BAR_WIDTH: usize = 30;
+
+/// How a battery has been used over some stretch of history
+pub struct Stats {
+    /// Seconds spent on battery and on AC, leaving out sleep
+    pub on_battery: i64,
+    pub on_ac:      i64,
+
+    /// Percent per hour while on battery, on average and at worst
+    pub average_drain: Option<f64>,
+    pub peak_drain:    Option<f64>,
+
+    /// Seconds on battery between unplugging and plugging back in
+    pub sessions: Vec<i64>,
+
+    /// Average level the charger got plugged in at
+    pub mean_plug_level: Option<f64>,
+
+    /// Times the battery was run below `DEEP_DISCHARGE_LEVEL`
+    pub deep_discharges: u32,
+
+    /// Seconds spent in each level band, from the lowest up
+    pub histogram: Vec<i64>,
+}
+
+impl Stats {
+    pub fn compute(states: &[BatteryState]) -> Stats {
+        let mut stats = Stats {
+            on_battery:      0,
+            on_ac:           0,
+            average_drain:   None,
+            peak_drain:      None,
+            sessions:        Vec::new(),
+            mean_plug_level: None,
+            deep_discharges: 0,
+            histogram:       vec![0; 100 / BAND_WIDTH],
+        };
+
+        let mut drained     = 0.0;
+        let mut plug_levels = Vec::new();
+        let mut session     = None;
+        let mut deep        = false;
+
+        for pair in states.windows(2) {
+            let (previous, state) = (&pair[0], &pair[1]);
+            let time = state.get_timestamp() as i64 - previous.get_timestamp() as i64;
+
+            // Asleep, off or a changed clock in between leaves no telling what happened
+            if time > 0 && time <= MAX_SAMPLE_GAP {
+                let band = (previous.get_level().max(0.0) as usize / BAND_WIDTH).min(100 / BAND_WIDTH - 1);
+                stats.histogram[band] += time;
+
+                if previous.get_charging() {
+                    stats.on_ac += time;
+                } else {
+                    stats.on_battery += time;
+                    drained          += (previous.get_level() - state.get_level()) as f64;
+
+                    *session.get_or_insert(0) += time;
+                }
+            }
+
+            if !state.get_charging() {
+                deep = deep || state.get_level() < DEEP_DISCHARGE_LEVEL;
+            }
+
+            // Plugging in ends a session
+            if !previous.get_charging() && state.get_charging() {
+                plug_levels.push(previous.get_level() as f64);
+
+                if let Some(length) = session.take() {
+                    stats.sessions.push(length);
+                }
+
+                if deep {
+                    stats.deep_discharges += 1;
+                    deep = false;
+                }
+            }
+        }
+
+        // The discharge still going on counts too
+        if let Some(length) = session {
+            stats.sessions.push(length);
+        }
+
+        if deep {
+            stats.deep_discharges += 1;
+        }
+
+        if stats.on_battery > 0 {
+            stats.average_drain = Some(drained / stats.on_battery as f64 * 3600.0);
+        }
+
+        if !plug_levels.is_empty() {
+            stats.mean_plug_level = Some(plug_levels.iter().sum::<f64>() / plug_levels.len() as f64);
+        }
+
+        stats.peak_drain = peak_drain(states);
+
+        stats
+    }
+
+    /// Lays the numbers out as text, for the terminal and the window alike
+    pub fn report(&self) -> String {
+        let total = (self.on_battery + self.on_ac).max(1) as f64;
+        let rate  = |rate: Option<f64>| rate.map_or("-".to_string(), |rate| format!("{:.1}%/h", rate));
+
+        let mut lines = vec![
+            format!("{:<18}{} ({:.0}%)", "On battery", format_duration(self.on_battery as f64), self.on_battery as f64 / total * 100.0),
+            format!("{:<18}{} ({:.0}%)", "On AC", format_duration(self.on_ac as f64), self.on_ac as f64 / total * 100.0),
+            format!("{:<18}{}", "Average drain", rate(self.average_drain)),
+            format!("{:<18}{}", "Peak drain", rate(self.peak_drain)),
+        ];
+
+        if let Some(&longest) = self.sessions.iter().max() {
+            let mean = self.sessions.iter().sum::<i64>() as f64 / self.sessions.len() as f64;
+
+            lines.push(format!("{:<18}{}, {} on average, longest {}",
+                               "Sessions", self.sessions.len(), format_duration(mean), format_duration(longest as f64)));
+        }
+
+        lines.push(format!("{:<18}{}", "Level at plug-in", self.mean_plug_level.map_or("-".to_string(), |level| format!("{:.0}% on average", level))));
+        lines.push(format!("{:<18}{} below {}%", "Deep discharges", self.deep_discharges, DEEP_DISCHARGE_LEVEL));
+
+        lines.push(String::new());
+        lines.push("Time at each level".to_string());
+
+        let longest = self.histogram.iter().cloned().max().unwrap_or(0).max(1) as f64;
+
+        for (band, &time) in self.histogram.iter().enumerate().rev() {
+            let bar = "█".repeat((time as f64 / longest * BAR_WIDTH as f64).round() as usize);
+
+            lines.push(format!("{:>4}-{:<4} {:<width$} {}",
+                               format!("{}", band * BAND_WIDTH), format!("{}%", (band + 1) * BAND_WIDTH),
+                               bar, format_duration(time as f64), width = BAR_WIDTH));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Finds the fastest the battery drained over any `PEAK_WINDOW` on battery, so a single
+/// noisy sample doesn't count as a spike
+fn peak_drain(states: &[BatteryState]) -> Option<f64> {
+    let mut peak  = None;
+    let mut start = 0;
+
+    for end in 1..states.len() {
+        let time = states[end].get_timestamp() as i64 - states[end - 1].get_timestamp() as i64;
+
+        // Only measure within one uninterrupted stretch on battery
+        if states[end].get_charging() || states[end - 1].get_charging() || time <= 0 || time > MAX_SAMPLE_GAP {
+            start = end;
+            continue;
+        }
+
+        // Bring the start up to the latest sample that still leaves a whole window
+        while start + 1 < end && states[end].get_timestamp() as i64 - states[start + 1].get_timestamp() as i64 >= PEAK_WINDOW {
+            start += 1;
+        }
+
+        let window = states[end].get_timestamp() as i64 - states[start].get_timestamp() as i64;
+        if window < PEAK_WINDOW {
+            continue;
+        }
+
+        let rate = (states[start].get_level() - states[end].get_level()) as f64 / window as f64 * 3600.0;
+        peak = Some(peak.map_or(rate, |peak: f64| peak.max(rate)));
+    }
+
+    peak
+}
+
+#[cfg(test)]
+mod tests {
+    use fixtures::{run, state};
+    use super::*;
+
+    #[test]
+    fn sessions_leave_out_sleep() {
+        // Half an hour on battery, two hours asleep, another half hour and then plugged in
+        let mut states = run(1000, 90.0, -0.5, false, 31);
+        states.extend(run(1000 + 1800 + 7200, 70.0, -0.5, false, 31));
+        states.extend(run(1000 + 1800 + 7200 + 1800 + 60, 55.0, 1.0, true, 11));
+
+        let stats = Stats::compute(&states);
+
+        // Sleeping doesn't end the session, the minute before the charger came on is part of it
+        assert_eq!(stats.sessions, vec![1800 + 1800 + 60]);
+        assert_eq!(stats.on_battery, 1800 + 1800 + 60);
+        assert_eq!(stats.on_ac, 600);
+        assert_eq!(stats.mean_plug_level, Some(55.0));
+        assert_eq!(stats.deep_discharges, 0);
+    }
+
+    #[test]
+    fn deep_discharge_still_going() {
+        let stats = Stats::compute(&run(1000, 30.0, -0.5, false, 30));
+
+        assert_eq!(stats.deep_discharges, 1);
+        assert_eq!(stats.sessions, vec![29 * 60]);
+        assert_eq!(stats.mean_plug_level, None);
+    }
+
+    #[test]
+    fn peak_drain_needs_a_whole_window() {
+        // Ten minutes at a percent a minute between slow stretches
+        let mut states = run(1000, 90.0, -0.1, false, 30);
+        states.extend(run(1000 + 1800, 87.0, -1.0, false, 11));
+        states.extend(run(1000 + 1800 + 660, 76.9, -0.1, false, 20));
+
+        let peak = Stats::compute(&states).peak_drain.unwrap();
+        assert!((peak - 60.0).abs() < 1e-3);
+
+        // A sample reading a percent low would be over 60%/h between neighbours, but is
+        // spread over the whole window instead
+        let mut states = run(1000, 90.0, -0.1, false, 30);
+        let level      = states[15].get_level();
+        states[15].set_level(level - 1.0);
+
+        let peak = Stats::compute(&states).peak_drain.unwrap();
+        assert!(peak < 13.0);
+
+        // Shorter than a window leaves nothing to go on
+        assert_eq!(Stats::compute(&run(1000, 90.0, -1.0, false, 10)).peak_drain, None);
+    }
+
+    #[test]
+    fn histogram_keeps_full_in_the_top_band() {
+        let states = [
+            state(1000, 100.0, true),
+            state(1060, 100.0, true),
+            state(1120,  90.0, false),
+            state(1180,  89.9, false),
+            state(1240,  89.0, false),
+        ];
+
+        let stats = Stats::compute(&states);
+
+        assert_eq!(stats.histogram.len(), 10);
+        assert_eq!(stats.histogram[9], 180);
+        assert_eq!(stats.histogram[8], 60);
+        assert!(stats.report().contains("90-100%"));
+    }
+}
@@ -0,0 +1,214 @@
+extern crate gtk;
+extern crate toml;
+
+use config::{self, Color, GraphConfig, SeriesColors};
+use gtk::prelude::*;
+use std::fs::File;
+use std::io::prelude::*;
+
+/// Everything about how the window and graph look
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Theme {
+    /// Take the window, text and accent colours from the GTK theme instead
+    pub follow_gtk: bool,
+
+    pub background: Color,
+
+    /// Text and gridlines, drawn at various opacities over the background
+    pub foreground: Color,
+
+    /// The line of text under the header
+    pub accent: Color,
+
+    /// Background of the box that appears when hovering the graph
+    pub tooltip: Color,
+
+    pub series: SeriesColors,
+
+    /// Colours for other devices drawn alongside this one, handed out in turn
+    pub overlays: Vec<Color>,
+
+    /// Opacity of the fill under the line, at the top and the bottom of the graph
+    pub fill_opacity: (f64, f64),
+
+    pub font: Font,
+    pub gridlines: Gridlines,
+    pub margins: Margins,
+
+    /// Extra CSS for the window, applied after ours
+    pub css: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Font {
+    pub family: String,
+    pub size: f64,
+
+    /// Used for the time axis, legend and tooltips
+    pub small_size: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Gridlines {
+    pub width: f64,
+    pub opacity: f64,
+
+    /// Alternating lengths of dashes and gaps, empty for solid lines
+    pub dash: Vec<f64>,
+}
+
+/// Space around the graph and its labels, in pixels
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Margins {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+
+impl Theme {
+    pub fn dark() -> Theme {
+        Theme {
+            follow_gtk:   false,
+            background:   (0x1d as f64 / 255.0, 0x1f as f64 / 255.0, 0x21 as f64 / 255.0),
+            foreground:   (1.0, 1.0, 1.0),
+            accent:       (0xab as f64 / 255.0, 0xdb as f64 / 255.0, 0xfb as f64 / 255.0),
+            tooltip:      (0.1, 0.1, 0.1),
+            series:       SeriesColors {
+                discharging: (0.7, 0.9, 1.0),
+                charging:    (0.7, 1.0, 0.6),
+                idle:        (0.8, 0.8, 0.8),
+            },
+            overlays:     vec![(1.0, 0.7, 0.4), (0.9, 0.6, 1.0), (1.0, 0.9, 0.5), (0.5, 1.0, 0.9)],
+            fill_opacity: (0.2, 0.0),
+            font:         Font { family: "Droid Sans".to_string(), size: 16.0, small_size: 12.0 },
+            gridlines:    Gridlines { width: 1.0, opacity: 0.2, dash: Vec::new() },
+            margins:      Margins { left: 10.0, top: 10.0, right: 20.0, bottom: 10.0 },
+            css:          String::new(),
+        }
+    }
+
+    pub fn light() -> Theme {
+        Theme {
+            background:   (0.98, 0.98, 0.98),
+            foreground:   (0.1, 0.1, 0.1),
+            accent:       (0.2, 0.45, 0.7),
+            tooltip:      (1.0, 1.0, 1.0),
+            series:       SeriesColors {
+                discharging: (0.15, 0.5, 0.8),
+                charging:    (0.3, 0.65, 0.2),
+                idle:        (0.5, 0.5, 0.5),
+            },
+            overlays:     vec![(0.85, 0.45, 0.1), (0.6, 0.3, 0.75), (0.75, 0.6, 0.0), (0.1, 0.6, 0.55)],
+            fill_opacity: (0.25, 0.0),
+            ..Theme::dark()
+        }
+    }
+
+    /// Picks up the window, text and accent colours of the GTK theme a widget is styled with
+    pub fn follow_gtk_colors(&mut self, style: &gtk::StyleContext) {
+        let lookup = |name: &str, fallback: Color| {
+            style.lookup_color(name).map_or(fallback, |rgba| (rgba.red, rgba.green, rgba.blue))
+        };
+
+        self.background = lookup("theme_bg_color",          self.background);
+        self.foreground = lookup("theme_fg_color",          self.foreground);
+        self.accent     = lookup("theme_selected_bg_color", self.accent);
+        self.tooltip    = self.background;
+    }
+
+    /// Styles the window. Following GTK leaves the window colours to the GTK theme.
+    pub fn css(&self) -> String {
+        let colors = if self.follow_gtk {
+            String::new()
+        } else {
+            format!("
+    window {{
+        background-color: {};
+    }}
+
+    #header {{
+        color: {};
+    }}
+
+    #subheader {{
+        color: {};
+    }}
+    ", css_color(self.background, 1.0), css_color(self.foreground, 0.94), css_color(self.accent, 1.0))
+        };
+
+        format!("{}
+    #header {{
+        padding: 15px 20px;
+        padding-bottom: 10px;
+    }}
+
+    #subheader {{
+        padding: 0px 20px;
+        padding-bottom: 15px;
+    }}
+
+    #stats {{
+        font-family: monospace;
+        padding: 10px 20px;
+    }}
+    {}", colors, self.css)
+    }
+}
+
+fn css_color((r, g, b): Color, alpha: f64) -> String {
+    format!("rgba({}, {}, {}, {})", (r * 255.0).round(), (g * 255.0).round(), (b * 255.0).round(), alpha)
+}
+
+/// Reads theme.toml from the config directory on top of the built-in theme it names as
+/// `base`, so it only has to list what it changes. Without one the dark theme is used.
+/// Any line colours set in config.toml's `[graph.colors]` go on top of that.
+pub fn load(config: &GraphConfig) -> Theme {
+    let mut theme = load_file();
+    config.colors.apply(&mut theme.series);
+
+    theme
+}
+
+fn load_file() -> Theme {
+    let path = config::config_dir().join("theme.toml");
+
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_)   => return Theme::dark(),
+    };
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect("Couldn't read theme.toml.");
+
+    let mut overrides = contents.parse::<toml::Value>().expect("Couldn't parse theme.toml.");
+
+    let base = match overrides.as_table_mut().and_then(|table| table.remove("base")) {
+        Some(toml::Value::String(ref name)) if name == "light" => Theme::light(),
+        Some(toml::Value::String(ref name)) if name == "dark"  => Theme::dark(),
+        Some(base) => panic!("Unrecognized base theme {}", base),
+        None       => Theme::dark(),
+    };
+
+    let mut theme = toml::Value::try_from(base).unwrap();
+    merge(&mut theme, overrides);
+
+    theme.try_into().expect("Couldn't parse theme.toml.")
+}
+
+/// Recursively replaces what's in `base` with what's in `overrides`, keeping the rest
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (&mut toml::Value::Table(ref mut base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                if let Some(existing) = base.get_mut(&key) {
+                    merge(existing, value);
+                    continue;
+                }
+
+                base.insert(key, value);
+            }
+        },
+        (base, overrides) => *base = overrides,
+    }
+}
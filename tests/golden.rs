@@ -0,0 +1,205 @@
+extern crate cairo;
+extern crate chrono;
+extern crate priv_battery;
+
+use chrono::{Local, TimeZone};
+use priv_battery::config::Config;
+use priv_battery::estimate::SlopeEstimator;
+use priv_battery::graph::BatteryState;
+use priv_battery::linegraph::View;
+use priv_battery::profile::{ProfileEstimator, UsageProfile};
+use priv_battery::render::{Format, render};
+use priv_battery::theme::Theme;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Once, ONCE_INIT};
+
+/// Channels can be this far off before a pixel counts as different, for antialiasing
+const CHANNEL_TOLERANCE: u8 = 24;
+
+/// Share of pixels allowed to differ, for the odd glyph edge rasterised differently
+const PIXEL_TOLERANCE: f64 = 0.002;
+
+/// Distance any coordinate in an SVG may move
+const COORDINATE_TOLERANCE: f64 = 0.05;
+
+static FONTS: Once = ONCE_INIT;
+
+/// Points fontconfig at the font shipped with the tests and nothing else, so text comes out
+/// the same whatever's installed. Has to happen before cairo first looks for a font.
+fn pin_fonts() {
+    FONTS.call_once(|| {
+        let fonts = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fonts");
+        let cache = env::temp_dir().join("priv-battery-golden-fontconfig");
+        let file  = env::temp_dir().join("priv-battery-golden-fonts.conf");
+
+        File::create(&file).unwrap().write_all(format!(
+            "<?xml version=\"1.0\"?>\n\
+             <!DOCTYPE fontconfig SYSTEM \"fonts.dtd\">\n\
+             <fontconfig>\n  <dir>{}</dir>\n  <cachedir>{}</cachedir>\n</fontconfig>\n",
+            fonts.display(), cache.display()).as_bytes()).unwrap();
+
+        env::set_var("FONTCONFIG_FILE", &file);
+    });
+}
+
+fn state(timestamp: u32, level: f32, charging: bool) -> BatteryState {
+    let mut state = BatteryState::new();
+    state.set_timestamp(timestamp);
+    state.set_level(level);
+    state.set_charging(charging);
+
+    state
+}
+
+fn run(start: u32, level: f32, rate: f32, charging: bool, count: usize) -> Vec<BatteryState> {
+    (0..count).map(|minute| state(start + minute as u32 * 60, level + rate * minute as f32, charging))
+              .collect()
+}
+
+/// Local time on a day without a clock change, so the axis comes out the same anywhere
+fn at(hour: u32, minute: u32) -> u32 {
+    Local.ymd(2017, 3, 28).and_hms(hour, minute, 0).timestamp() as u32
+}
+
+/// A morning of use, a nap, a charge and some more use
+fn history() -> Vec<BatteryState> {
+    let mut states = run(at(8, 0), 100.0, -0.3, false, 180);
+    states.extend(run(at(12, 0), 46.0, -0.3, false, 90));
+    states.extend(run(at(13, 30), 19.0, 1.0, true, 60));
+    states.extend(run(at(14, 30), 78.0, -0.25, false, 120));
+
+    states
+}
+
+fn read(path: &Path) -> Vec<u8> {
+    let mut contents = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+
+    contents
+}
+
+fn render_graph(format: Format) -> Vec<u8> {
+    pin_fonts();
+
+    let output = env::temp_dir().join(format!("priv-battery-golden-{:?}.{}", format, format.extension()));
+
+    let history   = history();
+    let view      = View { start: Some(at(7, 0) as i64), end: Some(at(19, 0) as i64) };
+    let estimator = SlopeEstimator { window: 60 * 5, charge_limit: 100.0 };
+    let usual     = ProfileEstimator { profile: UsageProfile::learn(&history) };
+
+    let mut theme = Theme::dark();
+    theme.font.family = "DejaVu Sans".to_string();
+
+    render(&output, format, 600, 300, 1.0, &history, &view, &estimator, &usual, &Config::default(), &theme);
+
+    let contents = read(&output);
+    fs::remove_file(&output).unwrap();
+
+    contents
+}
+
+/// Returns the reference to compare `rendered` with, unless `UPDATE_GOLDEN` is set, in which
+/// case `rendered` becomes the reference instead. Check any new ones by eye before committing.
+fn reference(format: Format, rendered: &[u8]) -> Option<Vec<u8>> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("graph.{}", format.extension()));
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(&path).unwrap().write_all(rendered).unwrap();
+        return None;
+    }
+
+    if !path.exists() {
+        panic!("There's no reference at {}, write one with UPDATE_GOLDEN=1 cargo test", path.display());
+    }
+
+    Some(read(&path))
+}
+
+/// Decodes a PNG into its size and pixels, one `u32` per pixel with the stride left out
+fn pixels(png: &[u8]) -> (i32, i32, Vec<u32>) {
+    let mut surface = cairo::ImageSurface::create_from_png(&mut &png[..]).unwrap();
+    let (width, height, stride) = (surface.get_width(), surface.get_height(), surface.get_stride());
+
+    let data = surface.get_data().unwrap();
+    let pixels = (0..height).flat_map(|y| (0..width).map(move |x| (y * stride + x * 4) as usize))
+                            .map(|offset| data[offset] as u32 | (data[offset + 1] as u32) << 8
+                                          | (data[offset + 2] as u32) << 16 | (data[offset + 3] as u32) << 24)
+                            .collect();
+
+    (width, height, pixels)
+}
+
+/// The numbers in every path, in the order they're drawn
+fn path_coordinates(svg: &str) -> Vec<f64> {
+    svg.split(" d=\"")
+       .skip(1)
+       .flat_map(|rest| rest[..rest.find('"').unwrap()]
+                            .split(|c: char| !(c.is_digit(10) || c == '.' || c == '-' || c == 'e'))
+                            .filter_map(|number| number.parse::<f64>().ok())
+                            .collect::<Vec<_>>())
+       .collect()
+}
+
+#[test]
+fn renders_png() {
+    let png       = render_graph(Format::Png);
+    let reference = match reference(Format::Png, &png) {
+        Some(reference) => reference,
+        None            => return,
+    };
+
+    let (width, height, actual)           = pixels(&png);
+    let (ref_width, ref_height, expected) = pixels(&reference);
+
+    assert_eq!((width, height), (ref_width, ref_height));
+    assert_eq!((width, height), (600, 300));
+
+    let channel = |pixel: u32, shift: u32| (pixel >> shift & 0xff) as i32;
+    let differing = actual.iter().zip(expected.iter())
+                          .filter(|&(&actual, &expected)| {
+                              [0, 8, 16, 24].iter().any(|&shift| (channel(actual, shift) - channel(expected, shift)).abs() > CHANNEL_TOLERANCE as i32)
+                          })
+                          .count();
+
+    assert!(differing as f64 <= PIXEL_TOLERANCE * actual.len() as f64,
+            "{} of {} pixels differ from tests/golden/graph.png", differing, actual.len());
+}
+
+#[test]
+fn renders_svg() {
+    let svg       = render_graph(Format::Svg);
+    let reference = match reference(Format::Svg, &svg) {
+        Some(reference) => String::from_utf8(reference).unwrap(),
+        None            => return,
+    };
+
+    let svg = String::from_utf8(svg).unwrap();
+
+    assert!(svg.contains("width=\"600pt\"") && svg.contains("height=\"300pt\""));
+
+    let actual   = path_coordinates(&svg);
+    let expected = path_coordinates(&reference);
+
+    assert_eq!(actual.len(), expected.len(), "the paths in graph.svg changed shape");
+
+    let moved = actual.iter().zip(expected.iter()).filter(|&(a, b)| (a - b).abs() > COORDINATE_TOLERANCE).count();
+    assert_eq!(moved, 0, "{} coordinates in graph.svg moved", moved);
+}
+
+#[test]
+fn renders_pdf() {
+    let pdf = render_graph(Format::Pdf);
+
+    // Compressed streams differ between cairo versions, so check it's one page of the right size
+    let text  = String::from_utf8_lossy(&pdf);
+    let pages = text.matches("/Type /Page").count() - text.matches("/Type /Pages").count();
+
+    assert!(pdf.starts_with(b"%PDF-"));
+    assert_eq!(pages, 1);
+    assert!(text.contains("/MediaBox [ 0 0 600 300 ]"));
+}